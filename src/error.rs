@@ -0,0 +1,115 @@
+use crate::jit::verifier::Divergence;
+use crate::memory::{Fault, GuestMemory};
+use iced_x86::IcedError;
+use std::fmt::Formatter;
+use std::{error, fmt, io, result};
+
+/// Everything that stops the JIT or the loader short of running the guest to
+/// its end.
+#[derive(Debug)]
+pub enum Error {
+    /// The word doesn't decode to an AArch64 instruction
+    UndefinedInstruction,
+    /// Neither the JIT nor the interpreter implement the op
+    UnknownOp,
+    /// An operand form the op isn't implemented for
+    UnsupportedOperand(String),
+    /// An emitter needed more host registers than are free
+    RegistersExhausted,
+    /// iced-x86 couldn't encode a block
+    Encode(IcedError),
+    /// A host address generated code can't reach from the guest state with a
+    /// 32-bit displacement
+    OutOfReach(u64),
+    /// Generated code left a value the runtime doesn't know, naming what it
+    /// was meant to be
+    InvalidState(&'static str, u64),
+    /// Mapping the code arena or switching its protection failed
+    CodeArena(io::Error),
+    /// The code arena couldn't be made executable again after a write, so no
+    /// translated code can run
+    CodeArenaPoisoned,
+    /// A block of this many bytes doesn't fit the code arena even when empty
+    BlockTooLarge(usize),
+    /// A guest access outside of the mapped address space
+    MemoryFault(Fault),
+    /// The NRO couldn't be read or mapped
+    Loader(io::Error),
+    /// The JIT and the interpreter disagree on a block, see `Context::verify`
+    Divergence(Box<Divergence>),
+    /// `error` was raised by the guest instruction at `pc`
+    Instruction {
+        pc: u64,
+        inst: String,
+        error: Box<Error>,
+    },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// Attaches the guest instruction at `pc` to the error, shown disassembled
+    /// or as its raw word if it doesn't decode.
+    pub fn at(self, pc: u64, memory: &GuestMemory) -> Error {
+        let inst = match memory.read_u32(pc) {
+            Ok(word) => match bad64::decode(word, pc) {
+                Ok(decoded) => decoded.to_string(),
+                Err(_) => format!("{:#010x}", word),
+            },
+            Err(_) => String::from("unmapped memory"),
+        };
+        Error::Instruction {
+            pc,
+            inst,
+            error: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UndefinedInstruction => write!(f, "Undefined instruction"),
+            Error::UnknownOp => write!(f, "Unknown op"),
+            Error::UnsupportedOperand(operand) => write!(f, "Unsupported operand {}", operand),
+            Error::RegistersExhausted => write!(f, "No free registers remaining"),
+            Error::Encode(err) => write!(f, "Can't encode block: {}", err),
+            Error::OutOfReach(addr) => write!(f, "{:#x} is out of reach of the guest state", addr),
+            Error::InvalidState(what, value) => write!(f, "Invalid {} {:#x}", what, value),
+            Error::CodeArena(err) => write!(f, "Code arena: {}", err),
+            Error::CodeArenaPoisoned => write!(f, "Code arena is no longer executable"),
+            Error::BlockTooLarge(size) => {
+                write!(f, "Block of {} bytes does not fit the code arena", size)
+            }
+            Error::MemoryFault(fault) => write!(f, "{:?}", fault),
+            Error::Loader(err) => write!(f, "Can't load the NRO: {}", err),
+            Error::Divergence(divergence) => divergence.fmt(f),
+            Error::Instruction { pc, inst, error } => {
+                write!(f, "{} in {} at {:#x}", error, inst, pc)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Encode(err) => Some(err),
+            Error::CodeArena(err) | Error::Loader(err) => Some(err),
+            Error::Instruction { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Fault> for Error {
+    fn from(fault: Fault) -> Self {
+        Error::MemoryFault(fault)
+    }
+}
+
+impl From<IcedError> for Error {
+    fn from(err: IcedError) -> Self {
+        Error::Encode(err)
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::{Error, Result};
+use memmap::MmapMut;
+use std::io;
+use std::ops::Range;
+
+/// Start of every allocation is aligned to this.
+const BLOCK_ALIGNMENT: usize = 16;
+
+/// One executable region that all translated code lives in. The mapping is
+/// never writable and executable at once, `modify` flips it to writable for
+/// the duration of a write.
+pub struct CodeArena {
+    /// Executable, except within `modify`. Its protection is switched in
+    /// place, so the code stays mapped when that fails.
+    map: MmapMut,
+    /// Unallocated ranges, sorted and coalesced
+    free: Vec<Range<usize>>,
+    /// Set once the mapping couldn't be made executable again, the code in it
+    /// can't run anymore
+    poisoned: bool,
+}
+
+impl CodeArena {
+    pub fn new(size: usize) -> Result<Self> {
+        let map = MmapMut::map_anon(size).map_err(Error::CodeArena)?;
+        protect(&map, libc::PROT_READ | libc::PROT_EXEC).map_err(Error::CodeArena)?;
+        Ok(CodeArena {
+            map,
+            free: vec![Range {
+                start: 0,
+                end: size,
+            }],
+            poisoned: false,
+        })
+    }
+
+    /// Host address of the arena start, offsets are relative to it.
+    pub fn base(&self) -> u64 {
+        self.map.as_ptr() as u64
+    }
+
+    pub fn size(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Fails with `Error::CodeArenaPoisoned` if the code in the arena can't
+    /// run anymore.
+    pub fn check(&self) -> Result<()> {
+        match self.poisoned {
+            true => Err(Error::CodeArenaPoisoned),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether a rel32 anywhere in the arena reaches the host address `target`.
+    pub fn in_reach(&self, target: u64) -> bool {
+        let start = self.base();
+        [start, start + self.size() as u64]
+            .iter()
+            .all(|from| i32::try_from(target.wrapping_sub(*from) as i64).is_ok())
+    }
+
+    /// Displacement of a rel32 at `offset` to the host address `target`, which
+    /// must be in reach.
+    pub fn rel32(&self, offset: usize, target: u64) -> i32 {
+        let next = self.base() + (offset + 4) as u64;
+        target.wrapping_sub(next) as i32
+    }
+
+    /// Reserves `len` bytes with first fit, returns `None` if no free range is
+    /// large enough.
+    pub fn alloc(&mut self, len: usize) -> Option<Range<usize>> {
+        let len = (len + BLOCK_ALIGNMENT - 1) & !(BLOCK_ALIGNMENT - 1);
+        let index = self.free.iter().position(|range| range.len() >= len)?;
+        let start = self.free[index].start;
+        self.free[index].start += len;
+        if self.free[index].is_empty() {
+            self.free.remove(index);
+        }
+        Some(start..start + len)
+    }
+
+    pub fn free(&mut self, range: Range<usize>) {
+        let index = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(index, range);
+
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start {
+            self.free[index].end = self.free.remove(index + 1).end;
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start {
+            self.free[index - 1].end = self.free.remove(index).end;
+        }
+    }
+
+    /// Makes the arena writable, hands it to `write` and makes it executable
+    /// again. If that last step fails the arena is poisoned.
+    pub fn modify<F>(&mut self, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut [u8]),
+    {
+        self.check()?;
+        protect(&self.map, libc::PROT_READ | libc::PROT_WRITE).map_err(Error::CodeArena)?;
+        write(&mut self.map);
+        if let Err(err) = protect(&self.map, libc::PROT_READ | libc::PROT_EXEC) {
+            self.poisoned = true;
+            return Err(Error::CodeArena(err));
+        }
+        Ok(())
+    }
+}
+
+/// Sets the protection of the whole mapping.
+fn protect(map: &MmapMut, prot: libc::c_int) -> io::Result<()> {
+    let addr = map.as_ptr() as *mut libc::c_void;
+    match unsafe { libc::mprotect(addr, map.len(), prot) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Writes `rel` into the rel32 at `offset`, `arena` is the window `modify`
+/// hands out.
+pub fn write_rel32(arena: &mut [u8], offset: usize, rel: i32) {
+    arena[offset..offset + 4].copy_from_slice(&rel.to_le_bytes());
+}
@@ -1,10 +1,18 @@
-use crate::jit::assembler::{Error, Result};
-use iced_x86::{BlockEncoder, BlockEncoderOptions, Code, IcedError, Instruction, InstructionBlock};
-use memmap::{Mmap, MmapMut};
+use crate::error::{Error, Result};
+use crate::jit::assembler::registers_handler::{
+    ARGUMENT_REGISTERS, CALLEE_SAVED_REGISTERS, STATE_REGISTER,
+};
+use iced_x86::{
+    BlockEncoder, BlockEncoderOptions, Code, IcedError, Instruction, InstructionBlock,
+    MemoryOperand, Register,
+};
 use std::result;
 
 pub type Inst = Instruction;
 
+const JMP_REL32: u8 = 0xe9;
+const CALL_REL32: u8 = 0xe8;
+
 pub struct Label {
     id: u64,
 }
@@ -15,19 +23,78 @@ impl Label {
     }
 }
 
+/// A patchable jump from a finished block to the block at guest `target`.
+/// While unlinked it continues right behind itself.
+pub struct LinkSite {
+    pub target: u64,
+    /// Offset of the jump's rel32 within the block
+    pub offset: usize,
+}
+
+/// A call to the host function at `fun`, whose rel32 is filled in once the
+/// block is placed.
+pub struct CallSite {
+    pub fun: u64,
+    /// Offset of the call's rel32 within the block
+    pub offset: usize,
+}
+
+/// An encoded block along with the sites to fill in when placing it.
+pub struct HostCode {
+    pub code: Vec<u8>,
+    pub links: Vec<LinkSite>,
+    pub calls: Vec<CallSite>,
+}
+
+/// An argument of a host call.
+pub enum CallArg {
+    Reg(Register),
+    Imm(u64),
+    /// Host address at this offset from the guest state
+    State(i64),
+}
+
 pub struct InstAssembler {
     insts: Vec<Inst>,
+    /// Host address of the guest state at translation time, `STATE_REGISTER`
+    /// holds it at runtime
+    state_base: u64,
     label_counter: u64,
+    /// Instruction index of each link jump and its guest target
+    links: Vec<(usize, u64)>,
+    /// Instruction index of each host call and the function it calls
+    calls: Vec<(usize, u64)>,
+    /// First instruction that couldn't be built, `finalize` returns it
+    error: Option<Error>,
 }
 
 impl InstAssembler {
-    pub fn new() -> Self {
+    pub fn new(state_base: u64) -> Self {
         InstAssembler {
             insts: Vec::new(),
+            state_base,
             label_counter: 0,
+            links: Vec::new(),
+            calls: Vec::new(),
+            error: None,
         }
     }
 
+    /// Displacement of the host address `addr` from the guest state. If it is
+    /// out of reach, `finalize` fails.
+    pub fn state_offset(&mut self, addr: u64) -> i64 {
+        let offset = addr.wrapping_sub(self.state_base) as i64;
+        if i32::try_from(offset).is_err() {
+            self.fail(Error::OutOfReach(addr));
+        }
+        offset
+    }
+
+    /// Records an error to be returned by `finalize`, the first one wins.
+    fn fail(&mut self, error: Error) {
+        self.error.get_or_insert(error);
+    }
+
     #[inline]
     pub fn add(&mut self, inst: Inst) {
         self.insts.push(inst);
@@ -38,20 +105,26 @@ impl InstAssembler {
         self.add(inst);
     }
 
+    /// Adds an instruction that may have failed to build. The failure is
+    /// returned by `finalize`.
     #[inline]
     pub fn uw_add(&mut self, inst: result::Result<Inst, IcedError>) {
-        self.add(inst.unwrap());
+        match inst {
+            Ok(inst) => self.add(inst),
+            Err(err) => self.fail(Error::Encode(err)),
+        }
     }
 
     pub fn uw_add_with_label(&mut self, inst: result::Result<Inst, IcedError>, label: &Label) {
-        let mut inst = inst.unwrap();
-        inst.set_ip(label.id);
-        self.add(inst);
+        match inst {
+            Ok(inst) => self.add_with_label(inst, label),
+            Err(err) => self.fail(Error::Encode(err)),
+        }
     }
 
     #[inline]
     pub fn add_branch(&mut self, code: Code, label: &Label) {
-        self.add(Inst::with_branch(code, label.id).unwrap());
+        self.uw_add(Inst::with_branch(code, label.id));
     }
 
     pub fn create_label(&mut self) -> Label {
@@ -59,22 +132,119 @@ impl InstAssembler {
         Label::new(self.label_counter)
     }
 
-    pub fn finalize(self) -> Result<Mmap> {
-        let inst_block = InstructionBlock::new(&self.insts, 0);
-        let block_encoder = match BlockEncoder::encode(64, inst_block, BlockEncoderOptions::NONE) {
-            Ok(encoder) => Ok(encoder),
-            Err(err) => Err(Error::new(err.to_string())),
-        }?;
-        let buf = block_encoder.code_buffer;
-
-        let mut mem = match MmapMut::map_anon(buf.len()) {
-            Ok(map) => Ok(map),
-            Err(err) => Err(Error::new(err.to_string())),
-        }?;
-        mem.copy_from_slice(&buf);
-        match mem.make_exec() {
-            Ok(map) => Ok(map),
-            Err(err) => Err(Error::new(err.to_string())),
+    /// Saves the callee-saved registers blocks use and points `STATE_REGISTER`
+    /// at the guest state, passed as the first argument. Blocks are called
+    /// with a 16 byte aligned stack, the body keeps it aligned.
+    pub fn emit_prologue(&mut self) {
+        for reg in CALLEE_SAVED_REGISTERS {
+            self.uw_add(Inst::with1(Code::Push_r64, reg));
         }
+        self.uw_add(Inst::with1(Code::Push_r64, STATE_REGISTER));
+        self.uw_add(Inst::with2(Code::Sub_rm64_imm8, Register::RSP, 8));
+        self.uw_add(Inst::with2(
+            Code::Mov_r64_rm64,
+            STATE_REGISTER,
+            Register::RDI,
+        ));
+    }
+
+    /// Undoes the prologue. The guest state is left in RDI, so a linked block
+    /// can be jumped to right after.
+    pub fn emit_epilogue(&mut self) {
+        self.uw_add(Inst::with2(
+            Code::Mov_r64_rm64,
+            Register::RDI,
+            STATE_REGISTER,
+        ));
+        self.uw_add(Inst::with2(Code::Add_rm64_imm8, Register::RSP, 8));
+        self.uw_add(Inst::with1(Code::Pop_r64, STATE_REGISTER));
+        for reg in CALLEE_SAVED_REGISTERS.iter().rev() {
+            self.uw_add(Inst::with1(Code::Pop_r64, *reg));
+        }
+    }
+
+    /// Calls the `extern "C"` function at `fun` following the SysV ABI, with
+    /// `result` receiving the return value. The prologue leaves the stack
+    /// aligned for the call. Only the callee-saved registers holding guest
+    /// registers survive it, callers must not keep values in scratch or
+    /// vector registers across it.
+    pub fn emit_host_call(&mut self, fun: u64, args: &[CallArg], result: Option<Register>) {
+        assert!(
+            args.len() <= ARGUMENT_REGISTERS.len(),
+            "Too many host call arguments"
+        );
+
+        // Going through the stack keeps register arguments from overwriting
+        // each other's sources
+        for arg in args {
+            if let CallArg::Reg(reg) = arg {
+                self.uw_add(Inst::with1(Code::Push_r64, *reg));
+            }
+        }
+        for (arg, dest) in args.iter().zip(ARGUMENT_REGISTERS).rev() {
+            if let CallArg::Reg(_) = arg {
+                self.uw_add(Inst::with1(Code::Pop_r64, dest));
+            }
+        }
+        for (arg, dest) in args.iter().zip(ARGUMENT_REGISTERS) {
+            match arg {
+                CallArg::Reg(_) => {}
+                CallArg::Imm(imm) => self.uw_add(Inst::with2(Code::Mov_r64_imm64, dest, *imm)),
+                CallArg::State(offset) => self.uw_add(Inst::with2(
+                    Code::Lea_r64_m,
+                    dest,
+                    MemoryOperand::with_base_displ(STATE_REGISTER, *offset),
+                )),
+            }
+        }
+
+        self.calls.push((self.insts.len(), fun));
+        self.uw_add(Inst::try_with_declare_byte_5(CALL_REL32, 0, 0, 0, 0));
+        if let Some(result) = result {
+            self.uw_add(Inst::with2(Code::Mov_r64_rm64, result, Register::RAX));
+        }
+    }
+
+    /// Emits a jump that can later be patched to the host code of the block at
+    /// guest `target`. Until then it continues with the next instruction.
+    pub fn add_link(&mut self, target: u64) {
+        self.links.push((self.insts.len(), target));
+        // Spelled out, the encoder would shorten a jmp to the next instruction
+        self.uw_add(Inst::try_with_declare_byte_5(JMP_REL32, 0, 0, 0, 0));
+    }
+
+    /// Encodes the block. The code is position independent, except for link
+    /// and call sites whose rel32 is filled in once the block is placed.
+    pub fn finalize(self) -> Result<HostCode> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let inst_block = InstructionBlock::new(&self.insts, 0);
+        let options = BlockEncoderOptions::RETURN_NEW_INSTRUCTION_OFFSETS;
+        let block_encoder = BlockEncoder::encode(64, inst_block, options)?;
+
+        // The rel32 follows the opcode byte
+        let offsets = &block_encoder.new_instruction_offsets;
+        let links = self
+            .links
+            .iter()
+            .map(|&(index, target)| LinkSite {
+                target,
+                offset: offsets[index] as usize + 1,
+            })
+            .collect();
+        let calls = self
+            .calls
+            .iter()
+            .map(|&(index, fun)| CallSite {
+                fun,
+                offset: offsets[index] as usize + 1,
+            })
+            .collect();
+        Ok(HostCode {
+            code: block_encoder.code_buffer,
+            links,
+            calls,
+        })
     }
 }
@@ -1,4 +1,4 @@
-use crate::jit::assembler::{Error, Result};
+use crate::error::{Error, Result};
 use iced_x86::Register;
 use std::collections::HashSet;
 
@@ -80,7 +80,35 @@ const REGS_8: [Register; 16] = [
 
 const REGS_8_H: [Register; 4] = [Register::AH, Register::BH, Register::CH, Register::DH];
 
-const CALLER_SAVED_REGISTERS: [Register; 9] = [
+/// Never handed out by `RegistersHandler`, link stubs and the guest state
+/// accessors in `utils` use it so they can't clobber an emitter's registers.
+pub const SCRATCH_REGISTER: Register = Register::R11;
+
+/// Points at the guest state block for the whole block, which is addressed
+/// relative to it.
+pub const STATE_REGISTER: Register = Register::R15;
+
+/// Hold guest registers across a block, see `RegisterAllocator`. Blocks save
+/// them and `STATE_REGISTER` on entry, six pushes keep the stack alignment.
+pub const CALLEE_SAVED_REGISTERS: [Register; 5] = [
+    Register::RBX,
+    Register::RBP,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+];
+
+/// Integer argument registers of the SysV calling convention, in order.
+pub const ARGUMENT_REGISTERS: [Register; 6] = [
+    Register::RDI,
+    Register::RSI,
+    Register::RDX,
+    Register::RCX,
+    Register::R8,
+    Register::R9,
+];
+
+const CALLER_SAVED_REGISTERS: [Register; 8] = [
     Register::RAX,
     Register::RCX,
     Register::RDX,
@@ -89,7 +117,6 @@ const CALLER_SAVED_REGISTERS: [Register; 9] = [
     Register::R8,
     Register::R9,
     Register::R10,
-    Register::R11,
 ];
 
 pub struct RegistersHandler {
@@ -114,7 +141,7 @@ impl RegistersHandler {
                 return Ok(register);
             }
         }
-        Err(Error::new("No free registers remaining".to_string()))
+        Err(Error::RegistersExhausted)
     }
 }
 
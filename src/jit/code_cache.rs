@@ -0,0 +1,255 @@
+use crate::error::{Error, Result};
+use crate::jit::assembler::code_arena::{write_rel32, CodeArena};
+use crate::jit::assembler::instructions_assembler::{HostCode, LinkSite};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+
+/// Host code budget used when none is configured.
+pub const DEFAULT_CODE_BUDGET: usize = 64 * 1024 * 1024;
+
+/// `jmp qword ptr [rip]`, followed by the host address it jumps to.
+const VENEER_JMP: [u8; 6] = [0xff, 0x25, 0, 0, 0, 0];
+
+struct Block {
+    /// Host code of the block within the arena
+    code: Range<usize>,
+    /// Guest instructions the block was translated from
+    guest_range: Range<u64>,
+    /// Guest PCs the block's link sites jump to, indexing into `links`
+    targets: Vec<u64>,
+    /// Bumped by dispatcher lookups and whenever a jump gets linked to the
+    /// block, as linked jumps bypass the dispatcher
+    last_used: u64,
+}
+
+/// Owns the translated blocks, keyed by the guest PC they start at, and the
+/// links between them.
+pub struct CodeCache {
+    arena: CodeArena,
+    blocks: HashMap<u64, Block>,
+    /// Link sites keyed by the guest PC they jump to, together with the guest
+    /// PC of the block holding them
+    links: HashMap<u64, Vec<(u64, LinkSite)>>,
+    /// Arena offset of the veneer jumping to each host function out of reach
+    /// of host calls
+    veneers: HashMap<u64, usize>,
+    clock: u64,
+}
+
+impl CodeCache {
+    /// Creates a cache holding at most `budget` bytes of host code, all in a
+    /// single arena. Blocks jump to each other with rel32 displacements, so the
+    /// budget is limited to 2 GiB.
+    pub fn new(budget: usize) -> Result<Self> {
+        if budget > i32::MAX as usize {
+            let error = io::Error::new(io::ErrorKind::InvalidInput, "Code budget over 2 GiB");
+            return Err(Error::CodeArena(error));
+        }
+        Ok(CodeCache {
+            arena: CodeArena::new(budget)?,
+            blocks: HashMap::new(),
+            links: HashMap::new(),
+            veneers: HashMap::new(),
+            clock: 0,
+        })
+    }
+
+    /// Returns the host code of the block starting at `pc` and the guest range
+    /// it was translated from. Fails once the arena is poisoned, its code
+    /// can't run.
+    pub fn lookup(&mut self, pc: u64) -> Result<Option<(u64, Range<u64>)>> {
+        self.arena.check()?;
+        self.clock += 1;
+        let block = match self.blocks.get_mut(&pc) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        block.last_used = self.clock;
+        let host_addr = self.arena.base() + block.code.start as u64;
+        Ok(Some((host_addr, block.guest_range.clone())))
+    }
+
+    /// Adds the block translated from `guest_range` and links it with the
+    /// blocks already present. Least recently used blocks are evicted until
+    /// the arena has room. Returns its host code.
+    pub fn insert(&mut self, guest_range: Range<u64>, code: HostCode) -> Result<u64> {
+        let pc = guest_range.start;
+        self.invalidate(pc)?;
+        let mut call_targets = Vec::new();
+        for site in &code.calls {
+            call_targets.push(self.call_target(site.fun)?);
+        }
+        let range = self.alloc(code.code.len())?;
+
+        let host_addr = self.arena.base() + range.start as u64;
+        let start = range.start;
+        let calls: Vec<(usize, i32)> = code
+            .calls
+            .iter()
+            .zip(call_targets)
+            .map(|(site, target)| {
+                let offset = start + site.offset;
+                (offset, self.arena.rel32(offset, target))
+            })
+            .collect();
+        self.arena.modify(|arena| {
+            arena[start..start + code.code.len()].copy_from_slice(&code.code);
+            for (offset, rel) in calls {
+                write_rel32(arena, offset, rel);
+            }
+        })?;
+
+        self.clock += 1;
+        self.blocks.insert(
+            pc,
+            Block {
+                code: range,
+                guest_range,
+                targets: code.links.iter().map(|site| site.target).collect(),
+                last_used: self.clock,
+            },
+        );
+        self.link_block(pc, code.links)?;
+        Ok(host_addr)
+    }
+
+    /// Whether any block was translated from guest code overlapping `range`.
+    pub fn has_code(&self, range: Range<u64>) -> bool {
+        self.blocks
+            .values()
+            .any(|block| block.guest_range.start < range.end && range.start < block.guest_range.end)
+    }
+
+    /// Drops every block translated from guest code overlapping `range`.
+    pub fn invalidate_range(&mut self, range: Range<u64>) -> Result<()> {
+        let overlapping: Vec<u64> = self
+            .blocks
+            .iter()
+            .filter(|(_, block)| {
+                block.guest_range.start < range.end && range.start < block.guest_range.end
+            })
+            .map(|(pc, _)| *pc)
+            .collect();
+        for pc in overlapping {
+            self.invalidate(pc)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the block starting at `pc`. Blocks linked to it are unlinked and
+    /// exit to the dispatcher again.
+    pub fn invalidate(&mut self, pc: u64) -> Result<()> {
+        let block = match self.blocks.remove(&pc) {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        self.arena.free(block.code);
+
+        for target in &block.targets {
+            if let Some(sites) = self.links.get_mut(target) {
+                sites.retain(|(source, _)| *source != pc);
+                if sites.is_empty() {
+                    self.links.remove(target);
+                }
+            }
+        }
+        // An unlinked jump continues right behind itself
+        let patches: Vec<(usize, i32)> = match self.links.get(&pc) {
+            Some(sites) => sites
+                .iter()
+                .map(|(source, site)| (self.blocks[source].code.start + site.offset, 0))
+                .collect(),
+            None => Vec::new(),
+        };
+        self.patch_links(&patches)
+    }
+
+    /// Reserves `len` bytes of the arena, evicting least recently used blocks
+    /// until it has room.
+    fn alloc(&mut self, len: usize) -> Result<Range<usize>> {
+        loop {
+            if let Some(range) = self.arena.alloc(len) {
+                return Ok(range);
+            }
+            if self.blocks.is_empty() {
+                return Err(Error::BlockTooLarge(len));
+            }
+            self.evict()?;
+        }
+    }
+
+    /// Host address a call to the host function `fun` goes to. Functions out
+    /// of rel32 reach are called through a veneer in the arena jumping to them.
+    fn call_target(&mut self, fun: u64) -> Result<u64> {
+        if self.arena.in_reach(fun) {
+            return Ok(fun);
+        }
+        if let Some(offset) = self.veneers.get(&fun) {
+            return Ok(self.arena.base() + *offset as u64);
+        }
+
+        let range = self.alloc(VENEER_JMP.len() + 8)?;
+        let start = range.start;
+        self.arena.modify(|arena| {
+            let (jmp, addr) = arena[range].split_at_mut(VENEER_JMP.len());
+            jmp.copy_from_slice(&VENEER_JMP);
+            addr[..8].copy_from_slice(&fun.to_le_bytes());
+        })?;
+        self.veneers.insert(fun, start);
+        Ok(self.arena.base() + start as u64)
+    }
+
+    fn evict(&mut self) -> Result<()> {
+        let oldest = self
+            .blocks
+            .iter()
+            .min_by_key(|(_, block)| block.last_used)
+            .map(|(pc, _)| *pc);
+        match oldest {
+            Some(pc) => self.invalidate(pc),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes each rel32 to its arena offset in one writable window.
+    fn patch_links(&mut self, patches: &[(usize, i32)]) -> Result<()> {
+        if patches.is_empty() {
+            return Ok(());
+        }
+        self.arena.modify(|arena| {
+            for (offset, rel) in patches {
+                write_rel32(arena, *offset, *rel);
+            }
+        })
+    }
+
+    /// Records the link sites of the freshly inserted block at `pc`, links
+    /// those whose target is present and links every site waiting for `pc`.
+    fn link_block(&mut self, pc: u64, sites: Vec<LinkSite>) -> Result<()> {
+        let base = self.arena.base();
+        let source_start = self.blocks[&pc].code.start;
+        let mut patches = Vec::new();
+        for site in &sites {
+            if let Some(target) = self.blocks.get_mut(&site.target) {
+                target.last_used = self.clock;
+                let offset = source_start + site.offset;
+                let target_code = base + target.code.start as u64;
+                patches.push((offset, self.arena.rel32(offset, target_code)));
+            }
+        }
+
+        // Sites of the block itself are added after, they are already patched
+        if let Some(waiting) = self.links.get(&pc) {
+            let target_code = base + source_start as u64;
+            for (source, site) in waiting {
+                let offset = self.blocks[source].code.start + site.offset;
+                patches.push((offset, self.arena.rel32(offset, target_code)));
+            }
+        }
+        for site in sites {
+            self.links.entry(site.target).or_default().push((pc, site));
+        }
+        self.patch_links(&patches)
+    }
+}
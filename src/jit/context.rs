@@ -1,193 +1,1024 @@
-use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
-use crate::jit::assembler::registers_handler::{map_reg_16, RegistersHandler};
-use crate::jit::parser::parse_inst;
+use crate::error::{Error, Result};
+use crate::jit::assembler::instructions_assembler::{HostCode, Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::{map_reg_32, map_reg_8};
+use crate::jit::code_cache::{CodeCache, DEFAULT_CODE_BUDGET};
+use crate::jit::interpreter;
+use crate::jit::parser::{inst_info, parse_inst, Flags};
+use crate::jit::register_allocator::RegisterAllocator;
 use crate::jit::utils;
-use bad64::Reg;
-use iced_x86::{Code, Decoder, DecoderOptions, Register};
-use memmap::Mmap;
-use std::collections::HashMap;
+use crate::jit::verifier::Reference;
+use crate::logging::{self, log, Category, Level};
+use crate::memory::{Fault, GuestMemory, PAGE_SIZE};
+use bad64::{Condition, Reg};
+use iced_x86::{Code, Decoder, DecoderOptions, MemoryOperand, Register};
 use std::mem;
+use std::ops::Range;
 
-const TEXT_OFFSET: u64 = 0x10000;
-
+/// Guest flags, evaluated lazily. Flag-setting instructions only record their
+/// operation and operands, the NZCV value is computed once something reads
+/// bits that cannot be recovered from re-running the host operation.
+#[derive(Clone)]
+#[repr(C)]
 pub struct NZCV {
     value: u64,
+    /// Encoded LazyFlags of the recorded operation, 0 if `value` is current
+    op: u64,
+    left: u64,
+    right: u64,
 }
 
 impl Default for NZCV {
     fn default() -> Self {
-        NZCV { value: 0x4 << 28 }
+        NZCV {
+            value: 0x4 << 28,
+            op: 0,
+            left: 0,
+            right: 0,
+        }
+    }
+}
+
+/// The NZCV bits of the flags register, the rest is RES0.
+pub const NZCV_MASK: u64 = 0xF << 28;
+
+/// Translation time knowledge about the guest flags in the block being
+/// emitted. The emitters go through it to access `NZCV`.
+#[derive(Default)]
+pub struct FlagsTracker {
+    /// The producer of the flags earlier in the block, if it is statically
+    /// known
+    pending: Option<LazyFlags>,
+    /// The flags of the instruction being emitted are overwritten before
+    /// anything can observe them
+    pub dead: bool,
+}
+
+/// The kind of operation that last set the flags.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum FlagsOp {
+    Add,
+    Sub,
+    /// AND/OR/XOR, which clear CF and OF like the ARM logical instructions
+    Logical,
+}
+
+/// A recorded flag-setting operation and the width it ran at.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct LazyFlags {
+    pub op: FlagsOp,
+    pub is_32bit: bool,
+}
+
+impl LazyFlags {
+    fn encode(self) -> u64 {
+        let op = match self.op {
+            FlagsOp::Add => 1,
+            FlagsOp::Sub => 2,
+            FlagsOp::Logical => 3,
+        };
+        op << 1 | self.is_32bit as u64
+    }
+
+    fn decode(value: u64) -> Result<Option<Self>> {
+        let op = match value >> 1 {
+            0 => return Ok(None),
+            1 => FlagsOp::Add,
+            2 => FlagsOp::Sub,
+            3 => FlagsOp::Logical,
+            _ => return Err(Error::InvalidState("lazy flags", value)),
+        };
+        Ok(Some(LazyFlags {
+            op,
+            is_32bit: value & 1 != 0,
+        }))
     }
 }
 
+/// The ARM pseudocode AddWithCarry, returning the result and NZCV in bits 28-31.
+pub fn add_with_carry(x: u64, y: u64, carry: bool, is_32bit: bool) -> (u64, u64) {
+    let bits = if is_32bit { 32 } else { 64 };
+    let mask = u64::MAX >> (64 - bits);
+    let (x, y) = (x & mask, y & mask);
+    let sign_extend = |value: u64| ((value << (64 - bits)) as i64 >> (64 - bits)) as i128;
+
+    let unsigned_sum = x as u128 + y as u128 + carry as u128;
+    let signed_sum = sign_extend(x) + sign_extend(y) + carry as i128;
+    let result = unsigned_sum as u64 & mask;
+
+    let n = (result >> (bits - 1)) & 1;
+    let z = (result == 0) as u64;
+    let c = (result as u128 != unsigned_sum) as u64;
+    let v = (sign_extend(result) != signed_sum) as u64;
+    (result, (n << 3 | z << 2 | c << 1 | v) << 28)
+}
+
+/// Maps `cond` to the x86 setcc reading the host flags of `op` directly, if
+/// one exists. ARM HI/LS test C and Z together, which x86 only offers with
+/// its inverted borrow flag.
+fn host_condition(op: FlagsOp, cond: Condition) -> Option<Code> {
+    let code = match (op, cond) {
+        (_, Condition::EQ) => Code::Sete_rm8,
+        (_, Condition::NE) => Code::Setne_rm8,
+        (FlagsOp::Sub, Condition::CS) => Code::Setae_rm8,
+        (FlagsOp::Sub, Condition::CC) => Code::Setb_rm8,
+        (_, Condition::CS) => Code::Setb_rm8,
+        (_, Condition::CC) => Code::Setae_rm8,
+        (_, Condition::MI) => Code::Sets_rm8,
+        (_, Condition::PL) => Code::Setns_rm8,
+        (_, Condition::VS) => Code::Seto_rm8,
+        (_, Condition::VC) => Code::Setno_rm8,
+        (FlagsOp::Sub, Condition::HI) => Code::Seta_rm8,
+        (FlagsOp::Sub, Condition::LS) => Code::Setbe_rm8,
+        (_, Condition::GE) => Code::Setge_rm8,
+        (_, Condition::LT) => Code::Setl_rm8,
+        (_, Condition::GT) => Code::Setg_rm8,
+        (_, Condition::LE) => Code::Setle_rm8,
+        _ => return None,
+    };
+    Some(code)
+}
+
 impl NZCV {
     pub fn borrow_mut_value(&mut self) -> &mut u64 {
         &mut self.value
     }
 
-    pub fn emit_update(&mut self, asm: &mut InstAssembler) {
-        asm.add(Inst::with(Code::Lahf));
-        asm.uw_add(Inst::with1(Code::Seto_rm8, Register::AL));
+    /// Returns the flags, computing them from the recorded operation first.
+    pub fn value(&mut self) -> Result<u64> {
+        self.materialize()?;
+        Ok(self.value)
+    }
 
-        let mut regs_handler = RegistersHandler::new();
-        regs_handler.reserve(Register::RAX);
-        let nzcv_reg = regs_handler.get_free().unwrap();
-        let process_reg = regs_handler.get_free().unwrap().full_register();
-        let process_reg_16 = map_reg_16(&process_reg);
+    /// Fails if the recorded operation isn't one generated code records.
+    pub fn check(&self) -> Result<()> {
+        LazyFlags::decode(self.op).map(|_| ())
+    }
 
-        asm.uw_add(Inst::with2(Code::Xor_r64_rm64, nzcv_reg, nzcv_reg));
+    fn materialize(&mut self) -> Result<()> {
+        if let Some(flags) = LazyFlags::decode(self.op)? {
+            self.value = match flags.op {
+                FlagsOp::Add => add_with_carry(self.left, self.right, false, flags.is_32bit).1,
+                FlagsOp::Sub => add_with_carry(self.left, !self.right, true, flags.is_32bit).1,
+                // Adding zero leaves C and V clear, like the logical operations
+                FlagsOp::Logical => add_with_carry(self.left, 0, false, flags.is_32bit).1,
+            };
+            self.op = 0;
+        }
+        Ok(())
+    }
 
-        // NZ
-        asm.uw_add(Inst::with2(Code::Xor_r64_rm64, process_reg, process_reg));
-        asm.uw_add(Inst::with2(
-            Code::Mov_r16_rm16,
-            process_reg_16,
-            Register::AX,
-        ));
-        asm.uw_add(Inst::with2(Code::And_rm64_imm32, process_reg, 0xC000u32));
-        asm.uw_add(Inst::with2(Code::Sal_rm64_imm8, process_reg, 16));
-        asm.uw_add(Inst::with2(Code::Or_r64_rm64, nzcv_reg, process_reg));
+    /// Replaces the flags with `value`, dropping the recorded operation.
+    pub fn set(&mut self, value: u64) {
+        self.value = value;
+        self.op = 0;
+    }
+}
+
+impl FlagsTracker {
+    /// Called when a new block starts, the previous producer is unknown there.
+    pub fn begin_block(&mut self) {
+        self.pending = None;
+    }
+
+    /// Records a flag-setting operation instead of computing NZCV. `left` and
+    /// `right` hold the operands of an add or subtract, logical operations
+    /// pass their result as `left`. Dead flags are not recorded at all.
+    pub fn emit_record(
+        &mut self,
+        asm: &mut InstAssembler,
+        nzcv: &mut NZCV,
+        flags: LazyFlags,
+        left: Register,
+        right: Register,
+    ) {
+        if self.dead {
+            return;
+        }
+        asm.emit_set_var(flags.encode(), &mut nzcv.op);
+        asm.emit_set_var(left, &mut nzcv.left);
+        asm.emit_set_var(right, &mut nzcv.right);
+        self.pending = Some(flags);
+    }
+
+    /// Brings the NZCV value up to date, clobbering `temp` and `temp2`. The
+    /// recorded operation is re-run on the host and its flags packed into
+    /// NZCV. Without a known producer the operation is picked at runtime.
+    pub fn emit_materialize(
+        &mut self,
+        asm: &mut InstAssembler,
+        nzcv: &mut NZCV,
+        temp: Register,
+        temp2: Register,
+    ) {
+        let pack_label = asm.create_label();
+        let end_label = asm.create_label();
+        match self.pending {
+            Some(flags) => emit_arm_flags(asm, nzcv, flags, temp, temp2),
+            None => {
+                asm.emit_var_to_reg(&nzcv.op, temp);
+                asm.uw_add(Inst::with2(Code::Test_rm64_r64, temp, temp));
+                asm.add_branch(Code::Je_rel32_64, &end_label);
+
+                let mut cases = Vec::new();
+                for op in [FlagsOp::Add, FlagsOp::Sub, FlagsOp::Logical] {
+                    for is_32bit in [false, true] {
+                        let flags = LazyFlags { op, is_32bit };
+                        let label = asm.create_label();
+                        asm.uw_add(Inst::with2(
+                            Code::Cmp_rm64_imm8,
+                            temp,
+                            flags.encode() as i32,
+                        ));
+                        asm.add_branch(Code::Je_rel32_64, &label);
+                        cases.push((flags, label));
+                    }
+                }
+                // Anything else is left for the dispatcher to report
+                asm.add_branch(Code::Jmp_rel32_64, &end_label);
+
+                for (flags, label) in cases {
+                    asm.add_with_label(Inst::with(Code::Nopd), &label);
+                    emit_arm_flags(asm, nzcv, flags, temp, temp2);
+                    asm.add_branch(Code::Jmp_rel32_64, &pack_label);
+                }
+            }
+        }
+
+        asm.add_with_label(Inst::with(Code::Nopd), &pack_label);
+        emit_pack_flags(asm, nzcv, temp, temp2);
+        asm.add_with_label(Inst::with(Code::Nopd), &end_label);
+        self.pending = None;
+    }
 
-        // C
-        asm.uw_add(Inst::with2(Code::Xor_r64_rm64, process_reg, process_reg));
+    /// Copies the guest carry to the host carry flag, or its inverse as x86
+    /// subtractions borrow. Clobbers `temp` and `temp2`.
+    pub fn emit_carry(
+        &mut self,
+        asm: &mut InstAssembler,
+        nzcv: &mut NZCV,
+        borrow: bool,
+        temp: Register,
+        temp2: Register,
+    ) {
+        self.emit_materialize(asm, nzcv, temp, temp2);
+        asm.emit_var_to_reg(&nzcv.value, temp);
+        asm.uw_add(Inst::with2(Code::Bt_rm64_imm8, temp, 29));
+        if borrow {
+            asm.add(Inst::with(Code::Cmc));
+        }
+    }
+
+    /// Sets NZCV from the host flags of an add, or of a subtract if `borrow`,
+    /// for operations the recorded ones can't express. Clobbers `temp` and
+    /// `temp2`. Dead flags are not set at all.
+    pub fn emit_set_host(
+        &mut self,
+        asm: &mut InstAssembler,
+        nzcv: &mut NZCV,
+        borrow: bool,
+        temp: Register,
+        temp2: Register,
+    ) {
+        if self.dead {
+            return;
+        }
+        if borrow {
+            asm.add(Inst::with(Code::Cmc));
+        }
+        emit_pack_flags(asm, nzcv, temp, temp2);
+        self.pending = None;
+    }
+
+    /// Leaves 1 in `dest` if `cond` holds for the current flags and 0 otherwise.
+    /// When the producer is known, its host operation is re-run on the
+    /// recorded operands and read with setcc. Otherwise the NZCV nibble
+    /// indexes a 16-bit table of the combinations satisfying `cond`.
+    pub fn emit_condition(
+        &mut self,
+        asm: &mut InstAssembler,
+        nzcv: &mut NZCV,
+        cond: Condition,
+        dest: Register,
+        temp: Register,
+    ) -> Result<()> {
+        if matches!(cond, Condition::AL | Condition::NV) {
+            asm.uw_add(Inst::with2(Code::Mov_r32_imm32, map_reg_32(&dest), 1));
+            return Ok(());
+        }
+
+        let lazy = self
+            .pending
+            .and_then(|flags| host_condition(flags.op, cond).map(|code| (flags, code)));
+        if let Some((flags, setcc)) = lazy {
+            emit_host_op(asm, nzcv, flags, dest, temp);
+            asm.uw_add(Inst::with1(setcc, map_reg_8(&dest)));
+        } else {
+            self.emit_materialize(asm, nzcv, dest, temp);
+            asm.emit_var_to_reg(&nzcv.value, dest);
+            asm.uw_add(Inst::with2(Code::Shr_rm64_imm8, dest, 28));
+            asm.uw_add(Inst::with2(
+                Code::Mov_r32_imm32,
+                map_reg_32(&temp),
+                condition_table(cond),
+            ));
+            asm.uw_add(Inst::with2(
+                Code::Bt_rm32_r32,
+                map_reg_32(&temp),
+                map_reg_32(&dest),
+            ));
+            asm.uw_add(Inst::with1(Code::Setb_rm8, map_reg_8(&dest)));
+        }
         asm.uw_add(Inst::with2(
-            Code::Mov_r16_rm16,
-            process_reg_16,
-            Register::AX,
+            Code::Movzx_r32_rm8,
+            map_reg_32(&dest),
+            map_reg_8(&dest),
         ));
-        // CF is inverted in ARM
-        asm.uw_add(Inst::with2(Code::Xor_rm16_imm8, process_reg_16, -1));
-        asm.uw_add(Inst::with2(Code::And_rm64_imm32, process_reg, 0x100));
-        asm.uw_add(Inst::with2(Code::Sal_rm64_imm8, process_reg, 21));
-        asm.uw_add(Inst::with2(Code::Or_r64_rm64, nzcv_reg, process_reg));
-
-        // V
-        asm.uw_add(Inst::with2(Code::Xor_r64_rm64, process_reg, process_reg));
+        Ok(())
+    }
+
+    pub fn emit_set(&mut self, asm: &mut InstAssembler, nzcv: &mut NZCV, value: u64) {
+        asm.emit_set_var(value, nzcv.borrow_mut_value());
+        asm.emit_set_var(0u64, &mut nzcv.op);
+        self.pending = None;
+    }
+
+    pub fn emit_set_reg(&mut self, asm: &mut InstAssembler, nzcv: &mut NZCV, src: Register) {
+        asm.emit_set_var(src, nzcv.borrow_mut_value());
+        asm.emit_set_var(0u64, &mut nzcv.op);
+        self.pending = None;
+    }
+}
+
+/// Re-runs the recorded operation on `left` and `right`, leaving its flags in
+/// the host flags. The carry of subtractions is left inverted, as x86 borrows.
+fn emit_host_op(
+    asm: &mut InstAssembler,
+    nzcv: &mut NZCV,
+    flags: LazyFlags,
+    left: Register,
+    right: Register,
+) {
+    let (left_op, right_op) = if flags.is_32bit {
+        (map_reg_32(&left), map_reg_32(&right))
+    } else {
+        (left, right)
+    };
+    asm.emit_var_to_reg(&nzcv.left, left);
+    asm.emit_var_to_reg(&nzcv.right, right);
+    let code = match (flags.op, flags.is_32bit) {
+        (FlagsOp::Add, false) => Code::Add_r64_rm64,
+        (FlagsOp::Add, true) => Code::Add_r32_rm32,
+        (FlagsOp::Sub, false) => Code::Cmp_r64_rm64,
+        (FlagsOp::Sub, true) => Code::Cmp_r32_rm32,
+        (FlagsOp::Logical, false) => Code::Test_rm64_r64,
+        (FlagsOp::Logical, true) => Code::Test_rm32_r32,
+    };
+    if flags.op == FlagsOp::Logical {
+        asm.uw_add(Inst::with2(code, left_op, left_op));
+    } else {
+        asm.uw_add(Inst::with2(code, left_op, right_op));
+    }
+}
+
+/// Stores the host flags as NZCV, the carry taken as ARM defines it, and
+/// drops the recorded operation. Clobbers `temp` and `temp2`.
+fn emit_pack_flags(asm: &mut InstAssembler, nzcv: &mut NZCV, temp: Register, temp2: Register) {
+    // Setcc and movzx leave the host flags alone, lea adds without
+    // touching them either
+    let (temp_32, temp2_32) = (map_reg_32(&temp), map_reg_32(&temp2));
+    asm.uw_add(Inst::with1(Code::Seto_rm8, map_reg_8(&temp)));
+    asm.uw_add(Inst::with2(Code::Movzx_r32_rm8, temp_32, map_reg_8(&temp)));
+    for (setcc, scale) in [
+        (Code::Setb_rm8, 2),
+        (Code::Sete_rm8, 4),
+        (Code::Sets_rm8, 8),
+    ] {
+        asm.uw_add(Inst::with1(setcc, map_reg_8(&temp2)));
         asm.uw_add(Inst::with2(
-            Code::Mov_r16_rm16,
-            process_reg_16,
-            Register::AX,
+            Code::Movzx_r32_rm8,
+            temp2_32,
+            map_reg_8(&temp2),
         ));
-        asm.uw_add(Inst::with2(Code::And_rm64_imm32, process_reg, 1));
-        asm.uw_add(Inst::with2(Code::Sal_rm64_imm8, process_reg, 28));
-        asm.uw_add(Inst::with2(Code::Or_r64_rm64, nzcv_reg, process_reg));
+        let mem = MemoryOperand::with_base_index_scale(temp, temp2, scale);
+        asm.uw_add(Inst::with2(Code::Lea_r64_m, temp, mem));
+    }
+    asm.uw_add(Inst::with2(Code::Shl_rm64_imm8, temp, 28));
+    asm.emit_set_var(temp, nzcv.borrow_mut_value());
+    asm.emit_set_var(0u64, &mut nzcv.op);
+}
 
-        asm.emit_set_var(nzcv_reg, self.borrow_mut_value());
+/// Like `emit_host_op`, but with the carry flag as ARM defines it.
+fn emit_arm_flags(
+    asm: &mut InstAssembler,
+    nzcv: &mut NZCV,
+    flags: LazyFlags,
+    left: Register,
+    right: Register,
+) {
+    emit_host_op(asm, nzcv, flags, left, right);
+    if flags.op == FlagsOp::Sub {
+        asm.add(Inst::with(Code::Cmc));
     }
+}
 
-    pub fn emit_get_z(&self, asm: &mut InstAssembler, dest: Register) {
-        asm.emit_var_to_reg(&self.value, dest);
-        asm.uw_add(Inst::with2(Code::Sar_rm64_imm8, dest, 30));
-        asm.uw_add(Inst::with2(Code::And_rm64_imm8, dest, 1));
+/// Evaluates `cond` against an NZCV register value, as ConditionHolds does.
+pub fn condition_holds(cond: Condition, nzcv: u64) -> bool {
+    let n = (nzcv >> 31) & 1 != 0;
+    let z = (nzcv >> 30) & 1 != 0;
+    let c = (nzcv >> 29) & 1 != 0;
+    let v = (nzcv >> 28) & 1 != 0;
+    match cond {
+        Condition::EQ => z,
+        Condition::NE => !z,
+        Condition::CS => c,
+        Condition::CC => !c,
+        Condition::MI => n,
+        Condition::PL => !n,
+        Condition::VS => v,
+        Condition::VC => !v,
+        Condition::HI => c && !z,
+        Condition::LS => !c || z,
+        Condition::GE => n == v,
+        Condition::LT => n != v,
+        Condition::GT => !z && n == v,
+        Condition::LE => z || n != v,
+        Condition::AL | Condition::NV => true,
     }
+}
 
-    pub fn emit_set(&mut self, asm: &mut InstAssembler, value: u64) {
-        asm.emit_set_var(value, self.borrow_mut_value());
+/// Returns the condition testing the opposite of `cond`. Like on hardware,
+/// inverting AL yields NV, which still always holds.
+pub fn invert_condition(cond: Condition) -> Condition {
+    match cond {
+        Condition::EQ => Condition::NE,
+        Condition::NE => Condition::EQ,
+        Condition::CS => Condition::CC,
+        Condition::CC => Condition::CS,
+        Condition::MI => Condition::PL,
+        Condition::PL => Condition::MI,
+        Condition::VS => Condition::VC,
+        Condition::VC => Condition::VS,
+        Condition::HI => Condition::LS,
+        Condition::LS => Condition::HI,
+        Condition::GE => Condition::LT,
+        Condition::LT => Condition::GE,
+        Condition::GT => Condition::LE,
+        Condition::LE => Condition::GT,
+        Condition::AL => Condition::NV,
+        Condition::NV => Condition::AL,
     }
 }
 
-#[derive(Default)]
+/// Bit i is set if `cond` holds for the NZCV nibble i.
+fn condition_table(cond: Condition) -> u32 {
+    (0..16u64)
+        .filter(|nibble| condition_holds(cond, nibble << 28))
+        .fold(0, |table, nibble| table | (1 << nibble))
+}
+
+/// The guest state block. Generated code addresses it relative to
+/// `STATE_REGISTER`, so it must not move while a block runs.
+#[derive(Clone, Default)]
+#[repr(C)]
 pub struct Registers {
-    x0: u64,
-    x1: u64,
+    x: [u64; 31],
+    sp: u64,
     pc: u64,
     pub nzcv: NZCV,
+    v: [u128; VREG_COUNT],
+    fpcr: u64,
+    fpsr: u64,
 }
 
 impl Registers {
-    pub fn borrow_mut_reg(&mut self, reg: Reg) -> &mut u64 {
-        match reg {
-            Reg::X0 => &mut self.x0,
-            Reg::X1 => &mut self.x1,
-            _ => panic!("Unmapped register {}", reg),
+    /// Returns the 64-bit slot backing a general-purpose register.
+    /// W registers alias the low half of their X counterpart and WSP aliases SP.
+    /// The zero registers have no storage, callers must special case them.
+    pub fn borrow_mut_reg(&mut self, reg: Reg) -> Result<&mut u64> {
+        Ok(self.borrow_mut_gpr(gpr_slot(reg)?))
+    }
+
+    /// Returns the 64-bit slot with index `slot`, as given by `gpr_slot`.
+    pub fn borrow_mut_gpr(&mut self, slot: usize) -> &mut u64 {
+        if slot == SP_SLOT {
+            &mut self.sp
+        } else {
+            &mut self.x[slot]
         }
     }
 
     pub fn borrow_mut_pc(&mut self) -> &mut u64 {
         &mut self.pc
     }
+
+    /// Returns the 128-bit slot backing a SIMD/FP register, whichever of the
+    /// B/H/S/D/Q/V views `reg` names.
+    pub fn borrow_mut_vreg(&mut self, reg: Reg) -> Result<&mut u128> {
+        match vreg_index(reg) {
+            Some(index) => Ok(self.borrow_mut_vslot(index)),
+            None => Err(Error::UnsupportedOperand(reg.to_string())),
+        }
+    }
+
+    /// Returns the 128-bit slot of vector register `index`.
+    pub fn borrow_mut_vslot(&mut self, index: usize) -> &mut u128 {
+        &mut self.v[index]
+    }
+
+    pub fn borrow_mut_fpcr(&mut self) -> &mut u64 {
+        &mut self.fpcr
+    }
+
+    pub fn borrow_mut_fpsr(&mut self) -> &mut u64 {
+        &mut self.fpsr
+    }
+}
+
+/// Maps X0-X30 and W0-W30 to their index in the register file.
+fn gpr_index(reg: Reg) -> Option<usize> {
+    let value = reg as u32;
+    if (Reg::X0 as u32..=Reg::X30 as u32).contains(&value) {
+        Some((value - Reg::X0 as u32) as usize)
+    } else if (Reg::W0 as u32..=Reg::W30 as u32).contains(&value) {
+        Some((value - Reg::W0 as u32) as usize)
+    } else {
+        None
+    }
+}
+
+pub const SP_SLOT: usize = 31;
+
+/// Whether `reg` is a general-purpose register view, including SP but not the
+/// zero registers.
+pub fn is_gpr(reg: Reg) -> bool {
+    matches!(reg, Reg::SP | Reg::WSP) || gpr_index(reg).is_some()
+}
+
+/// Index of the 64-bit slot backing a general-purpose register view, X0-X30
+/// followed by SP.
+pub fn gpr_slot(reg: Reg) -> Result<usize> {
+    match reg {
+        Reg::SP | Reg::WSP => Ok(SP_SLOT),
+        _ => gpr_index(reg).ok_or_else(|| Error::UnsupportedOperand(reg.to_string())),
+    }
+}
+
+pub const VREG_COUNT: usize = 32;
+
+const VREG_BANKS: [Reg; 6] = [Reg::V0, Reg::B0, Reg::H0, Reg::S0, Reg::D0, Reg::Q0];
+
+/// Maps every SIMD/FP view to its index in the vector register file.
+/// bad64 places the zero register of each bank between register 30 and 31.
+fn vreg_index(reg: Reg) -> Option<usize> {
+    let value = reg as u32;
+    VREG_BANKS
+        .iter()
+        .find_map(|bank| match value.checked_sub(*bank as u32) {
+            Some(offset) if offset < 31 => Some(offset as usize),
+            Some(32) => Some(31),
+            _ => None,
+        })
+}
+
+pub fn is_vreg(reg: Reg) -> bool {
+    vreg_index(reg).is_some()
+}
+
+pub fn is_w_reg(reg: Reg) -> bool {
+    let value = reg as u32;
+    (Reg::W0 as u32..=Reg::WSP as u32).contains(&value)
+}
+
+pub fn is_zero_reg(reg: Reg) -> bool {
+    matches!(reg, Reg::XZR | Reg::WZR)
+}
+
+/// Why a block returned to the dispatcher.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// Continue at the guest PC stored in the registers
+    Branch = 0,
+    /// The guest PC reached memory that holds no code
+    EndOfCode = 1,
+    /// The instruction at the guest PC has no emitter and is left to the
+    /// interpreter
+    Interpret = 2,
+    /// A guest memory access faulted, `Context::run` returns the fault
+    Fault = 3,
+}
+
+impl TryFrom<u64> for ExitReason {
+    type Error = Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(ExitReason::Branch),
+            1 => Ok(ExitReason::EndOfCode),
+            2 => Ok(ExitReason::Interpret),
+            3 => Ok(ExitReason::Fault),
+            _ => Err(Error::InvalidState("block exit", value)),
+        }
+    }
+}
+
+fn emit_return(asm: &mut InstAssembler, exit: ExitReason) {
+    asm.uw_add(Inst::with2(Code::Mov_r64_imm64, Register::RAX, exit as u64));
+    asm.add(Inst::with(Code::Retnq));
 }
 
 pub struct Context {
-    text: Vec<u32>,
-    cached_functions: HashMap<usize, Mmap>,
+    pub code_cache: CodeCache,
+    pub register_allocator: RegisterAllocator,
+    pub flags_tracker: FlagsTracker,
+    /// Translation time only: guest address of the instruction being emitted
+    pub translation_pc: u64,
     pub registers: Registers,
+    pub memory: GuestMemory,
+    /// Runs every block through the interpreter as well, from the same
+    /// state, and fails at the first difference. Blocks aren't linked
+    /// meanwhile, so each returns to the dispatcher to be checked.
+    pub verify: bool,
+    /// Raised by a host call from generated code, which can't return it, with
+    /// its instruction attached. The block exits right after and the
+    /// dispatcher returns it.
+    error: Option<Error>,
 }
 
 impl Context {
-    pub fn new(text: Vec<u32>) -> Self {
-        Context {
-            text,
-            cached_functions: HashMap::new(),
-            registers: Registers::default(),
+    pub fn new(memory: GuestMemory, entry: u64) -> Result<Self> {
+        Context::with_code_budget(memory, entry, DEFAULT_CODE_BUDGET)
+    }
+
+    /// Like `new`, keeping at most `code_budget` bytes of translated code.
+    pub fn with_code_budget(memory: GuestMemory, entry: u64, code_budget: usize) -> Result<Self> {
+        let registers = Registers {
+            pc: entry,
+            sp: memory.stack_top(),
+            ..Default::default()
+        };
+
+        Ok(Context {
+            code_cache: CodeCache::new(code_budget)?,
+            register_allocator: RegisterAllocator::new(),
+            flags_tracker: FlagsTracker::default(),
+            translation_pc: 0,
+            registers,
+            memory,
+            verify: false,
+            error: None,
+        })
+    }
+
+    /// Runs guest code until a block reaches the end of the code. Blocks leave
+    /// the next guest PC in the registers and return to this loop, which looks
+    /// up or compiles the block there, or interprets the instruction there.
+    /// On an error the registers hold the state before the failing
+    /// instruction, the PC pointing at it.
+    pub fn run(&mut self) -> Result<ExitReason> {
+        let exit = loop {
+            let exit = self.execute_block(self.registers.pc)?;
+            if let Some(error) = self.error.take() {
+                return Err(error);
+            }
+            match exit {
+                ExitReason::Branch => {}
+                ExitReason::Interpret => self.interpret()?,
+                exit => break exit,
+            }
+        };
+        if logging::enabled(Category::Regs, Level::Info) {
+            self.print_regs()?;
         }
+        Ok(exit)
     }
 
-    pub fn run(&mut self) {
-        self.execute_fn(0);
+    /// Executes the instruction at the guest PC, which has no emitter. Stores
+    /// to translated code drop it, like they do from generated code.
+    fn interpret(&mut self) -> Result<()> {
+        log!(Block, Debug, "Interpreting {:#x}", self.registers.pc);
+        let pc = self.registers.pc;
+        let write = interpreter::step(&mut self.registers, &mut self.memory)?;
+        if let Some(write) = write {
+            if self.memory.has_code_pages(write.range()) {
+                self.code_write(pc, write.addr, write.previous.len() as u64);
+            }
+        }
+        self.error.take().map_or(Ok(()), Err)
     }
 
-    pub fn get_addr(&self) -> u64 {
-        utils::get_var_addr(self)
+    /// Called by generated code when a `size` byte access at guest `addr` is
+    /// out of bounds. The block leaves right after, with the PC at the
+    /// faulting instruction.
+    pub extern "C" fn memory_fault(&mut self, addr: u64, size: u64) {
+        let size = size as usize;
+        let error = Error::MemoryFault(Fault { addr, size });
+        self.error = Some(error.at(self.registers.pc, &self.memory));
     }
 
-    pub extern "C" fn branch(&mut self, relative_addr: i64) {
-        let addr = (self.registers.pc - TEXT_OFFSET) as i64 + relative_addr;
-        self.execute_fn(addr as usize);
+    /// Called by generated code after the store at guest `pc` wrote `size`
+    /// bytes to a page holding translated code. Drops the translations of the
+    /// written bytes and unmarks the pages left without code. The calling
+    /// block may be among them, its code stays in place until the dispatcher
+    /// inserts a new block.
+    pub extern "C" fn code_write(&mut self, pc: u64, addr: u64, size: u64) {
+        let range = addr..addr + size;
+        if let Err(error) = self.code_cache.invalidate_range(range.clone()) {
+            self.error = Some(error.at(pc, &self.memory));
+            return;
+        }
+
+        let mut page = range.start & !(PAGE_SIZE - 1);
+        while page < range.end {
+            let page_range = page..page + PAGE_SIZE;
+            let has_code = self.code_cache.has_code(page_range.clone());
+            self.memory.set_code_pages(page_range, has_code);
+            page += PAGE_SIZE;
+        }
     }
 
-    fn execute_fn(&mut self, addr: usize) {
-        println!("Executing 0x{:x}", addr);
+    /// Translates the guest code at `addr`, returning the host code and the
+    /// guest range it was translated from. Instructions whose operands their
+    /// emitter doesn't support end the block early, leaving them to the
+    /// interpreter. Returns `None` if that leaves nothing to translate.
+    fn compile_block(&mut self, addr: u64) -> Result<Option<(HostCode, Range<u64>)>> {
+        let mut end = None;
+        let (asm, guest_range, exit) = loop {
+            match self.translate_block(addr, end) {
+                Err(Error::Instruction { pc, error, .. })
+                    if matches!(*error, Error::UnsupportedOperand(_)) =>
+                {
+                    log!(Block, Debug, "Interpreting {:#x} instead: {}", pc, error);
+                    end = Some(pc);
+                }
+                result => break result?,
+            }
+        };
+        if guest_range.is_empty() && exit == ExitReason::Interpret {
+            return Ok(None);
+        }
+        let code = asm.finalize()?;
 
-        if self.cached_functions.contains_key(&addr) {
-            println!("0x{:x} is cached", addr);
-        } else {
-            let mut asm = InstAssembler::new();
+        log!(
+            Block,
+            Info,
+            "Compiled {:#x}-{:#x} into {} bytes",
+            guest_range.start,
+            guest_range.end,
+            code.code.len()
+        );
+        if logging::enabled(Category::Guest, Level::Debug) {
+            for pc in guest_range.clone().step_by(4) {
+                match self.memory.read_u32(pc).map(|inst| bad64::decode(inst, pc)) {
+                    Ok(Ok(inst)) => log!(Guest, Debug, "{:016X} {}", pc, inst),
+                    _ => log!(Guest, Debug, "{:016X} <undecodable>", pc),
+                }
+            }
+        }
+        if logging::enabled(Category::Host, Level::Debug) {
+            let mut decoder = Decoder::new(64, &code.code, DecoderOptions::NONE);
+            for inst in &mut decoder {
+                log!(Host, Debug, "{:016X} {}", inst.ip(), inst);
+            }
+        }
+        Ok(Some((code, guest_range)))
+    }
 
-            for inst_index in (addr / 4)..self.text.len() {
-                let pc = TEXT_OFFSET + inst_index as u64 * 4;
+    /// Emits the block at `addr`, stopping before `end` if given.
+    fn translate_block(
+        &mut self,
+        addr: u64,
+        end: Option<u64>,
+    ) -> Result<(InstAssembler, Range<u64>, ExitReason)> {
+        // The first pass only tells the register allocator which guest
+        // registers the block accesses where
+        self.register_allocator.begin_recording();
+        self.emit_block(addr, end)?;
+        self.register_allocator.begin_allocating();
+        self.emit_block(addr, end)
+    }
+
+    /// Emits one pass over the block at `addr`, also returning how it exits
+    /// after its last instruction.
+    fn emit_block(
+        &mut self,
+        addr: u64,
+        end: Option<u64>,
+    ) -> Result<(InstAssembler, Range<u64>, ExitReason)> {
+        let mut asm = InstAssembler::new(utils::get_var_addr(&self.registers));
+        self.flags_tracker.begin_block();
+        asm.emit_prologue();
+
+        let mut pc = addr;
+        let exit = loop {
+            if Some(pc) == end {
+                break ExitReason::Interpret;
+            }
+            let inst = match self.memory.read_u32(pc) {
+                Ok(inst) => inst,
+                Err(_) => break ExitReason::EndOfCode,
+            };
+            let parsed = parse_inst(pc, inst).map_err(|error| error.at(pc, &self.memory))?;
+            let (inst, info) = match parsed {
+                (inst, Some(info)) => (inst, info),
+                // The block ends before the instruction, the dispatcher
+                // interprets it and continues after it
+                (_, None) => break ExitReason::Interpret,
+            };
+            self.flags_tracker.dead =
+                !info.flags_written.is_empty() && self.flags_overwritten_after(pc, end);
+            // The PC is only needed in memory to report faults, branches and
+            // side exits store their own
+            if info.can_fault {
                 asm.emit_set_var(pc, self.registers.borrow_mut_pc());
+            }
 
-                let inst = self.text[inst_index];
-                let should_continue = parse_inst(self, &mut asm, &inst);
-                if !should_continue {
-                    break;
-                }
+            self.translation_pc = pc;
+            let should_continue = (info.emit)(self, &mut asm, inst.operands())
+                .map_err(|error| error.at(pc, &self.memory))?;
+            if should_continue == info.ends_block {
+                return Err(Error::InvalidState("block end", pc));
             }
+            pc += 4;
+            if info.ends_block {
+                break ExitReason::Branch;
+            }
+        };
 
-            asm.add(Inst::with(Code::Retnq));
+        // Branches already stored their target
+        if exit != ExitReason::Branch {
+            asm.emit_set_var(pc, self.registers.borrow_mut_pc());
+        }
+        self.emit_exit(&mut asm, exit);
+        Ok((asm, addr..pc, exit))
+    }
 
-            println!();
+    /// Whether the block overwrites all flags after the instruction at `pc`,
+    /// before anything reads them or may leave the block.
+    fn flags_overwritten_after(&self, pc: u64, end: Option<u64>) -> bool {
+        let mut pc = pc + 4;
+        loop {
+            if Some(pc) == end {
+                return false;
+            }
+            let info = self
+                .memory
+                .read_u32(pc)
+                .ok()
+                .and_then(|inst| bad64::decode(inst, pc).ok())
+                .and_then(|inst| inst_info(&inst));
+            let info = match info {
+                Some(info) => info,
+                None => return false,
+            };
+            if !info.flags_read.is_empty() || info.ends_block || info.can_fault {
+                return false;
+            }
+            if info.flags_written == Flags::NZCV {
+                return true;
+            }
+            pc += 4;
+        }
+    }
 
-            let mem = asm.finalize().unwrap();
+    /// Writes back the guest registers held in host registers and restores
+    /// the host registers the block saved on entry.
+    fn emit_leave(&mut self, asm: &mut InstAssembler) {
+        self.register_allocator.emit_flush(asm, &mut self.registers);
+        asm.emit_epilogue();
+    }
 
-            let mut decoder = Decoder::new(64, &mem, DecoderOptions::NONE);
-            for inst in &mut decoder {
-                println!("{:016X} {}", inst.ip(), inst);
+    /// Returns from the block to the dispatcher.
+    pub fn emit_exit(&mut self, asm: &mut InstAssembler, exit: ExitReason) {
+        self.emit_leave(asm);
+        emit_return(asm, exit);
+    }
+
+    /// Leaves the block through a link site, which jumps straight into the
+    /// block at guest `target` once that is compiled. Until then it returns to
+    /// the dispatcher.
+    pub fn emit_link_exit(&mut self, asm: &mut InstAssembler, target: u64) {
+        self.emit_leave(asm);
+        if !self.verify {
+            asm.add_link(target);
+        }
+        emit_return(asm, ExitReason::Branch);
+    }
+
+    fn execute_block(&mut self, addr: u64) -> Result<ExitReason> {
+        log!(Block, Trace, "Executing {:#x}", addr);
+
+        let (host_addr, guest_end) = match self.code_cache.lookup(addr)? {
+            Some((host_addr, guest_range)) => {
+                log!(Block, Trace, "{:#x} is cached", addr);
+                (host_addr, guest_range.end)
             }
+            None => {
+                // Blocks that would only exit to the interpreter aren't cached
+                let (code, guest_range) = match self.compile_block(addr)? {
+                    Some(block) => block,
+                    None => return Ok(ExitReason::Interpret),
+                };
+                self.memory.set_code_pages(guest_range.clone(), true);
+                let guest_end = guest_range.end;
+                (self.code_cache.insert(guest_range, code)?, guest_end)
+            }
+        };
+
+        // Recorded once the block marked its pages, stores to them end it
+        let reference = if self.verify {
+            Reference::record(&self.registers, &mut self.memory, guest_end)?
+        } else {
+            None
+        };
 
-            let fun: extern "C" fn() = unsafe { mem::transmute(mem.as_ptr()) };
-            self.cached_functions.insert(addr, mem);
-            fun();
+        let fun: extern "C" fn(*mut Registers) -> u64 = unsafe { mem::transmute(host_addr) };
+        let exit = ExitReason::try_from(fun(&mut self.registers))?;
+        self.registers.nzcv.check()?;
+
+        if let Some(reference) = reference {
+            if let Some(divergence) = reference.check(&mut self.registers, &self.memory)? {
+                return Err(Error::Divergence(Box::new(divergence)));
+            }
         }
+        if logging::enabled(Category::Regs, Level::Trace) {
+            self.print_regs()?;
+        }
+        Ok(exit)
+    }
+
+    fn print_regs(&mut self) -> Result<()> {
+        for (index, value) in self.registers.x.iter().enumerate() {
+            eprintln!("x{}: {:#016x}", index, value);
+        }
+        eprintln!("sp: {:#016x}", self.registers.sp);
+        eprintln!("pc: {:#016x}", self.registers.pc);
+        let nzcv = self.registers.nzcv.value()?;
+        eprintln!("nzcv: {:#016x}", nzcv);
+        eprintln!("n: {}", (nzcv >> 31) & 1);
+        eprintln!("z: {}", (nzcv >> 30) & 1);
+        eprintln!("c: {}", (nzcv >> 29) & 1);
+        eprintln!("v: {}", (nzcv >> 28) & 1);
+        for (index, value) in self.registers.v.iter().enumerate() {
+            eprintln!("v{}: {:#034x}", index, value);
+        }
+        eprintln!("fpcr: {:#010x}", self.registers.fpcr);
+        eprintln!("fpsr: {:#010x}", self.registers.fpsr);
+        eprintln!();
+        Ok(())
+    }
 
-        self.print_regs();
+    /// Loads a guest register into `dest`. W registers are zero extended and
+    /// the zero registers read as 0.
+    pub fn emit_get_reg(
+        &mut self,
+        assembler: &mut InstAssembler,
+        src: Reg,
+        dest: Register,
+    ) -> Result<()> {
+        if is_zero_reg(src) {
+            assembler.uw_add(Inst::with2(Code::Xor_r64_rm64, dest, dest));
+            return Ok(());
+        }
+        self.register_allocator
+            .emit_read(assembler, &mut self.registers, src, dest)
+    }
+
+    /// Stores `src` into a guest register. W writes zero the upper half and
+    /// writes to the zero registers are discarded.
+    pub fn emit_set_reg(
+        &mut self,
+        assembler: &mut InstAssembler,
+        src: Register,
+        dest: Reg,
+    ) -> Result<()> {
+        if is_zero_reg(dest) {
+            return Ok(());
+        }
+        self.register_allocator
+            .emit_write(assembler, &mut self.registers, src, dest)
     }
 
-    fn print_regs(&self) {
-        println!();
-        println!("x0: {:#016x}", self.registers.x0);
-        println!("x1: {:#016x}", self.registers.x1);
-        println!("pc: {:#016x}", self.registers.pc);
-        println!("nzcv: {:#016x}", self.registers.nzcv.value);
-        println!("n: {}", (self.registers.nzcv.value >> 31) & 1);
-        println!("z: {}", (self.registers.nzcv.value >> 30) & 1);
-        println!("c: {}", (self.registers.nzcv.value >> 29) & 1);
-        println!("v: {}", (self.registers.nzcv.value >> 28) & 1);
-        println!();
+    /// Loads a SIMD/FP register into the xmm register `dest`. Views narrower
+    /// than 128 bits are zero extended.
+    pub fn emit_get_vreg(
+        &mut self,
+        assembler: &mut InstAssembler,
+        src: Reg,
+        dest: Register,
+    ) -> Result<()> {
+        let size = src.size();
+        assembler.emit_var_to_xmm(self.registers.borrow_mut_vreg(src)?, dest, size)
     }
 
-    pub fn emit_get_reg(&mut self, assembler: &mut InstAssembler, src: Reg, dest: Register) {
-        assembler.emit_var_to_reg(self.registers.borrow_mut_reg(src), dest);
+    /// Stores the xmm register `src` into a SIMD/FP register. Like on hardware,
+    /// writing a B/H/S/D view clears the rest of the vector.
+    pub fn emit_set_vreg(
+        &mut self,
+        assembler: &mut InstAssembler,
+        src: Register,
+        dest: Reg,
+    ) -> Result<()> {
+        let size = dest.size();
+        assembler.emit_set_xmm_var(src, self.registers.borrow_mut_vreg(dest)?, size)
     }
 }
@@ -1,39 +1,349 @@
-use crate::jit::assembler::instructions_assembler::InstAssembler;
-use crate::jit::context::Context;
-use bad64::Operand;
+use crate::error::{Error, Result};
+use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::{
+    map_reg_16, map_reg_32, map_reg_8, RegistersHandler,
+};
+use crate::jit::context::{is_w_reg, Context, FlagsOp, LazyFlags};
+use bad64::{Imm, Operand, Reg, Shift};
+use iced_x86::{Code, Register};
+
+pub fn get_reg(operand: &Operand) -> Result<Reg> {
+    match operand {
+        Operand::Reg { reg, .. } => Ok(*reg),
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+}
+
+pub fn get_imm(operand: &Operand) -> Result<u64> {
+    match operand {
+        Operand::Imm32 { imm, shift } | Operand::Imm64 { imm, shift } => {
+            let imm = match imm {
+                Imm::Unsigned(imm) => *imm,
+                Imm::Signed(imm) => *imm as u64,
+            };
+            match shift {
+                None => Ok(imm),
+                Some(Shift::LSL(amount)) => Ok(imm << amount),
+                Some(_) => Err(Error::UnsupportedOperand(operand.to_string())),
+            }
+        }
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+}
+
+/// Returns `reg` at the width of the guest operation.
+pub fn op_reg(reg: Register, is_32bit: bool) -> Register {
+    if is_32bit {
+        map_reg_32(&reg)
+    } else {
+        reg
+    }
+}
+
+/// Materializes the flexible second operand of a data processing instruction
+/// into `dest`: an immediate, a plain register, a shifted register or an
+/// extended register.
+pub fn emit_operand(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operand: &Operand,
+    dest: Register,
+    is_32bit: bool,
+) -> Result<()> {
+    let (reg, shift) = match operand {
+        Operand::Imm32 { .. } | Operand::Imm64 { .. } => {
+            let mut imm = get_imm(operand)?;
+            if is_32bit {
+                imm &= 0xFFFFFFFF;
+            }
+            asm.uw_add(Inst::with2(Code::Mov_r64_imm64, dest, imm));
+            return Ok(());
+        }
+        Operand::Reg { reg, .. } => (*reg, None),
+        Operand::ShiftReg { reg, shift } => (*reg, Some(*shift)),
+        _ => return Err(Error::UnsupportedOperand(operand.to_string())),
+    };
+
+    context.emit_get_reg(asm, reg, dest)?;
+    let dest_op = op_reg(dest, is_32bit);
+    let (shift_code, amount) = match shift {
+        None => return Ok(()),
+        Some(Shift::LSL(amount)) => (Code::Shl_rm64_imm8, amount),
+        Some(Shift::LSR(amount)) => (Code::Shr_rm64_imm8, amount),
+        Some(Shift::ASR(amount)) => (Code::Sar_rm64_imm8, amount),
+        Some(Shift::ROR(amount)) => (Code::Ror_rm64_imm8, amount),
+        Some(extend) => {
+            let (extend_inst, amount) = match extend {
+                Shift::UXTB(amount) => (
+                    Some(Inst::with2(
+                        Code::Movzx_r32_rm8,
+                        map_reg_32(&dest),
+                        map_reg_8(&dest),
+                    )),
+                    amount,
+                ),
+                Shift::UXTH(amount) => (
+                    Some(Inst::with2(
+                        Code::Movzx_r32_rm16,
+                        map_reg_32(&dest),
+                        map_reg_16(&dest),
+                    )),
+                    amount,
+                ),
+                Shift::SXTB(amount) => (
+                    Some(Inst::with2(Code::Movsx_r64_rm8, dest, map_reg_8(&dest))),
+                    amount,
+                ),
+                Shift::SXTH(amount) => (
+                    Some(Inst::with2(Code::Movsx_r64_rm16, dest, map_reg_16(&dest))),
+                    amount,
+                ),
+                Shift::SXTW(amount) => (
+                    Some(Inst::with2(Code::Movsxd_r64_rm32, dest, map_reg_32(&dest))),
+                    amount,
+                ),
+                // W sources are already zero extended by emit_get_reg
+                Shift::UXTW(amount) | Shift::UXTX(amount) | Shift::SXTX(amount) => (None, amount),
+                _ => return Err(Error::UnsupportedOperand(operand.to_string())),
+            };
+            if let Some(extend_inst) = extend_inst {
+                asm.uw_add(extend_inst);
+            }
+            (Code::Shl_rm64_imm8, amount)
+        }
+    };
+
+    if amount != 0 {
+        let shift_code = if is_32bit {
+            match shift_code {
+                Code::Shl_rm64_imm8 => Code::Shl_rm32_imm8,
+                Code::Shr_rm64_imm8 => Code::Shr_rm32_imm8,
+                Code::Sar_rm64_imm8 => Code::Sar_rm32_imm8,
+                _ => Code::Ror_rm32_imm8,
+            }
+        } else {
+            shift_code
+        };
+        asm.uw_add(Inst::with2(shift_code, dest_op, amount));
+    }
+    Ok(())
+}
+
+fn emit_add_sub(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    op: FlagsOp,
+    set_flags: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 3);
+    let dest = get_reg(&operands[0])?;
+    let is_32bit = is_w_reg(dest);
+
+    let mut regs_handler = RegistersHandler::new();
+    let left_reg = regs_handler.get_free()?;
+    let right_reg = regs_handler.get_free()?;
+
+    context.emit_get_reg(asm, get_reg(&operands[1])?, left_reg)?;
+    emit_operand(context, asm, &operands[2], right_reg, is_32bit)?;
+
+    if set_flags {
+        let flags = LazyFlags { op, is_32bit };
+        context.flags_tracker.emit_record(
+            asm,
+            &mut context.registers.nzcv,
+            flags,
+            left_reg,
+            right_reg,
+        );
+    }
+
+    let code = match (op, is_32bit) {
+        (FlagsOp::Add, false) => Code::Add_r64_rm64,
+        (FlagsOp::Add, true) => Code::Add_r32_rm32,
+        (FlagsOp::Sub, false) => Code::Sub_r64_rm64,
+        (FlagsOp::Sub, true) => Code::Sub_r32_rm32,
+        (FlagsOp::Logical, _) => panic!("Logical operations are emitted by emitter_bit"),
+    };
+    asm.uw_add(Inst::with2(
+        code,
+        op_reg(left_reg, is_32bit),
+        op_reg(right_reg, is_32bit),
+    ));
+
+    context.emit_set_reg(asm, left_reg, dest)?;
+    Ok(true)
+}
+
+/// ADC/SBC and their flag-setting forms, which add the guest carry, or
+/// subtract its inverse.
+fn emit_add_sub_carry(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    subtract: bool,
+    set_flags: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 3);
+    let dest = get_reg(&operands[0])?;
+    let is_32bit = is_w_reg(dest);
+
+    let mut regs_handler = RegistersHandler::new();
+    let left_reg = regs_handler.get_free()?;
+    let right_reg = regs_handler.get_free()?;
+    let temp_reg = regs_handler.get_free()?;
+    let temp2_reg = regs_handler.get_free()?;
+
+    context.emit_get_reg(asm, get_reg(&operands[1])?, left_reg)?;
+    context.emit_get_reg(asm, get_reg(&operands[2])?, right_reg)?;
+
+    context.flags_tracker.emit_carry(
+        asm,
+        &mut context.registers.nzcv,
+        subtract,
+        temp_reg,
+        temp2_reg,
+    );
+    let code = match (subtract, is_32bit) {
+        (false, false) => Code::Adc_r64_rm64,
+        (false, true) => Code::Adc_r32_rm32,
+        (true, false) => Code::Sbb_r64_rm64,
+        (true, true) => Code::Sbb_r32_rm32,
+    };
+    asm.uw_add(Inst::with2(
+        code,
+        op_reg(left_reg, is_32bit),
+        op_reg(right_reg, is_32bit),
+    ));
+    if set_flags {
+        context.flags_tracker.emit_set_host(
+            asm,
+            &mut context.registers.nzcv,
+            subtract,
+            temp_reg,
+            temp2_reg,
+        );
+    }
+
+    context.emit_set_reg(asm, left_reg, dest)?;
+    Ok(true)
+}
+
+fn neg_operands(operands: &[Operand]) -> Result<[Operand; 3]> {
+    assert_eq!(operands.len(), 2);
+    let zero_reg = if is_w_reg(get_reg(&operands[0])?) {
+        Reg::WZR
+    } else {
+        Reg::XZR
+    };
+    Ok([
+        operands[0],
+        Operand::Reg {
+            reg: zero_reg,
+            arrspec: None,
+        },
+        operands[1],
+    ])
+}
 
 pub fn emit_add(
     context: &mut Context,
     assembler: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
-    assert_eq!(operands.len(), 3);
-    true
+) -> Result<bool> {
+    emit_add_sub(context, assembler, operands, FlagsOp::Add, false)
+}
+
+pub fn emit_adds(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_add_sub(context, assembler, operands, FlagsOp::Add, true)
 }
 
 pub fn emit_adr(
     context: &mut Context,
     assembler: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
+) -> Result<bool> {
     assert_eq!(operands.len(), 2);
-    true
+    let dest = get_reg(&operands[0])?;
+    let addr = match &operands[1] {
+        Operand::Label(Imm::Unsigned(addr)) => *addr,
+        operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+    };
+
+    let mut regs_handler = RegistersHandler::new();
+    let value_reg = regs_handler.get_free()?;
+
+    assembler.uw_add(Inst::with2(Code::Mov_r64_imm64, value_reg, addr));
+    context.emit_set_reg(assembler, value_reg, dest)?;
+    Ok(true)
 }
 
 pub fn emit_sub(
     context: &mut Context,
     assembler: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
-    assert_eq!(operands.len(), 3);
-    true
+) -> Result<bool> {
+    emit_add_sub(context, assembler, operands, FlagsOp::Sub, false)
 }
 
 pub fn emit_subs(
     context: &mut Context,
     assembler: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
-    assert_eq!(operands.len(), 3);
-    true
+) -> Result<bool> {
+    emit_add_sub(context, assembler, operands, FlagsOp::Sub, true)
+}
+
+pub fn emit_neg(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    let operands = neg_operands(operands)?;
+    emit_add_sub(context, assembler, &operands, FlagsOp::Sub, false)
+}
+
+pub fn emit_negs(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    let operands = neg_operands(operands)?;
+    emit_add_sub(context, assembler, &operands, FlagsOp::Sub, true)
+}
+
+pub fn emit_adc(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_add_sub_carry(context, assembler, operands, false, false)
+}
+
+pub fn emit_adcs(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_add_sub_carry(context, assembler, operands, false, true)
+}
+
+pub fn emit_sbc(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_add_sub_carry(context, assembler, operands, true, false)
+}
+
+pub fn emit_sbcs(
+    context: &mut Context,
+    assembler: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_add_sub_carry(context, assembler, operands, true, true)
 }
@@ -1,12 +1,171 @@
-use crate::jit::assembler::instructions_assembler::InstAssembler;
-use crate::jit::context::Context;
-use bad64::Operand;
+use crate::error::Result;
+use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::RegistersHandler;
+use crate::jit::context::{is_w_reg, Context, FlagsOp, LazyFlags};
+use crate::jit::emitter_arithmetic::{emit_operand, get_reg, op_reg};
+use bad64::{Operand, Reg};
+use iced_x86::Code;
+
+#[derive(Copy, Clone)]
+enum BitOp {
+    And,
+    Orr,
+    Eor,
+}
+
+/// Emits `dest = left op (invert ? !right : right)`. Bitmask immediates come
+/// already expanded from the decoder, so they go through `emit_operand` like
+/// the shifted register forms.
+fn emit_logical(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    op: BitOp,
+    invert: bool,
+    set_flags: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 3);
+    let dest = get_reg(&operands[0])?;
+    let is_32bit = is_w_reg(dest);
+
+    let mut regs_handler = RegistersHandler::new();
+    let left_reg = regs_handler.get_free()?;
+    let right_reg = regs_handler.get_free()?;
+
+    context.emit_get_reg(asm, get_reg(&operands[1])?, left_reg)?;
+    emit_operand(context, asm, &operands[2], right_reg, is_32bit)?;
+
+    let left_op = op_reg(left_reg, is_32bit);
+    let right_op = op_reg(right_reg, is_32bit);
+    if invert {
+        let code = if is_32bit {
+            Code::Not_rm32
+        } else {
+            Code::Not_rm64
+        };
+        asm.uw_add(Inst::with1(code, right_op));
+    }
+
+    let code = match (op, is_32bit) {
+        (BitOp::And, false) => Code::And_r64_rm64,
+        (BitOp::And, true) => Code::And_r32_rm32,
+        (BitOp::Orr, false) => Code::Or_r64_rm64,
+        (BitOp::Orr, true) => Code::Or_r32_rm32,
+        (BitOp::Eor, false) => Code::Xor_r64_rm64,
+        (BitOp::Eor, true) => Code::Xor_r32_rm32,
+    };
+    asm.uw_add(Inst::with2(code, left_op, right_op));
+
+    context.emit_set_reg(asm, left_reg, dest)?;
+    if set_flags {
+        let flags = LazyFlags {
+            op: FlagsOp::Logical,
+            is_32bit,
+        };
+        context.flags_tracker.emit_record(
+            asm,
+            &mut context.registers.nzcv,
+            flags,
+            left_reg,
+            left_reg,
+        );
+    }
+    Ok(true)
+}
+
+fn zero_reg_for(reg: Reg) -> Operand {
+    let reg = if is_w_reg(reg) { Reg::WZR } else { Reg::XZR };
+    Operand::Reg { reg, arrspec: None }
+}
 
 pub fn emit_and(
     context: &mut Context,
     assembler: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
-    assert_eq!(operands.len(), 3);
-    true
+) -> Result<bool> {
+    emit_logical(context, assembler, operands, BitOp::And, false, false)
+}
+
+pub fn emit_ands(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::And, false, true)
+}
+
+pub fn emit_tst(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let operands = [
+        zero_reg_for(get_reg(&operands[0])?),
+        operands[0],
+        operands[1],
+    ];
+    emit_logical(context, asm, &operands, BitOp::And, false, true)
+}
+
+pub fn emit_bic(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::And, true, false)
+}
+
+pub fn emit_bics(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::And, true, true)
+}
+
+pub fn emit_orr(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::Orr, false, false)
+}
+
+pub fn emit_orn(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::Orr, true, false)
+}
+
+pub fn emit_mvn(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let operands = [
+        operands[0],
+        zero_reg_for(get_reg(&operands[0])?),
+        operands[1],
+    ];
+    emit_logical(context, asm, &operands, BitOp::Orr, true, false)
+}
+
+pub fn emit_eor(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::Eor, false, false)
+}
+
+pub fn emit_eon(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_logical(context, asm, operands, BitOp::Eor, true, false)
 }
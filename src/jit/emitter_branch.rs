@@ -1,60 +1,275 @@
+use crate::error::{Error, Result};
 use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::RegistersHandler;
 use crate::jit::context::Context;
-use bad64::{Imm, Operand};
+use crate::jit::emitter_arithmetic::{get_imm, get_reg};
+use bad64::{Condition, Imm, Operand, Reg};
 use iced_x86::{Code, Register};
-use crate::jit::assembler::registers_handler::RegistersHandler;
-use crate::jit::utils;
-
-fn get_label(operand: &Operand) -> u64 {
-    *match operand {
-        Operand::Label(imm) => match imm {
-            Imm::Unsigned(imm) => imm,
-            _ => panic!(""),
-        },
-        _ => panic!("Branch must have a label"),
+
+fn get_label(operand: &Operand) -> Result<u64> {
+    match operand {
+        Operand::Label(Imm::Unsigned(imm)) => Ok(*imm),
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
     }
 }
 
-fn cmp_and_branch(context: &mut Context, asm: &mut InstAssembler, code_cond: Code, addr: u64) {
-    let mut regs_handler = RegistersHandler::new();
-    let z_reg = regs_handler.get_free().unwrap();
+/// Leaves the guest address held in `target` as the next PC. The dispatcher
+/// looks it up in the code cache, so it may come from a register.
+fn emit_branch_reg(context: &mut Context, asm: &mut InstAssembler, target: Register) {
+    asm.emit_set_var(target, context.registers.borrow_mut_pc());
+}
+
+/// Direct branches leave the block right away through a link site, which
+/// jumps straight into the target block once that is compiled.
+fn emit_branch_imm(context: &mut Context, asm: &mut InstAssembler, addr: u64) {
+    asm.emit_set_var(addr, context.registers.borrow_mut_pc());
+    context.emit_link_exit(asm, addr);
+}
+
+fn next_pc(context: &Context) -> u64 {
+    context.translation_pc + 4
+}
+
+/// Emits a two-way branch. `emit_test` sets the host flags and returns the
+/// jcc that skips the branch to `addr` and falls through to the next
+/// instruction instead. Both ways leave the block.
+fn emit_cond_branch<F>(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    addr: u64,
+    emit_test: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut Context, &mut InstAssembler) -> Result<Code>,
+{
+    let not_taken_label = asm.create_label();
+
+    let skip_code = emit_test(context, asm)?;
+    asm.add_branch(skip_code, &not_taken_label);
+    emit_branch_imm(context, asm, addr);
+
+    asm.add_with_label(Inst::with(Code::Nopd), &not_taken_label);
+    emit_branch_imm(context, asm, next_pc(context));
+    Ok(())
+}
+
+/// Branches to `addr` if `cond` holds for the guest flags.
+fn cond_branch(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    cond: Condition,
+    addr: u64,
+) -> Result<()> {
+    emit_cond_branch(context, asm, addr, |context, asm| {
+        let mut regs_handler = RegistersHandler::new();
+        let cond_reg = regs_handler.get_free()?;
+        let temp_reg = regs_handler.get_free()?;
+
+        context.flags_tracker.emit_condition(
+            asm,
+            &mut context.registers.nzcv,
+            cond,
+            cond_reg,
+            temp_reg,
+        )?;
+        asm.uw_add(Inst::with2(Code::Test_rm64_r64, cond_reg, cond_reg));
+        Ok(Code::Je_rel32_64)
+    })
+}
 
-    let context_addr = context.get_addr();
-    let call_label = asm.create_label();
-    let end_label = asm.create_label();
+macro_rules! emit_b_cond {
+    ($name:ident, $cond:expr) => {
+        pub fn $name(
+            context: &mut Context,
+            asm: &mut InstAssembler,
+            operands: &[Operand],
+        ) -> Result<bool> {
+            assert_eq!(operands.len(), 1);
+            let addr = get_label(&operands[0])?;
+            cond_branch(context, asm, $cond, addr)?;
+            Ok(false)
+        }
+    };
+}
+
+emit_b_cond!(emit_beq, Condition::EQ);
+emit_b_cond!(emit_bne, Condition::NE);
+emit_b_cond!(emit_bcs, Condition::CS);
+emit_b_cond!(emit_bcc, Condition::CC);
+emit_b_cond!(emit_bmi, Condition::MI);
+emit_b_cond!(emit_bpl, Condition::PL);
+emit_b_cond!(emit_bvs, Condition::VS);
+emit_b_cond!(emit_bvc, Condition::VC);
+emit_b_cond!(emit_bhi, Condition::HI);
+emit_b_cond!(emit_bls, Condition::LS);
+emit_b_cond!(emit_bge, Condition::GE);
+emit_b_cond!(emit_blt, Condition::LT);
+emit_b_cond!(emit_bgt, Condition::GT);
+emit_b_cond!(emit_ble, Condition::LE);
+emit_b_cond!(emit_bal, Condition::AL);
+emit_b_cond!(emit_bnv, Condition::NV);
+
+pub fn emit_b(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 1);
+    let addr = get_label(&operands[0])?;
+    emit_branch_imm(context, asm, addr);
+    Ok(false)
+}
+
+pub fn emit_bl(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 1);
+    let addr = get_label(&operands[0])?;
 
-    context.registers.nzcv.emit_get_z(asm, z_reg);
-    asm.uw_add(Inst::with2(Code::Cmp_rm64_imm8, z_reg, 1));
-    asm.add_branch(code_cond, &call_label);
-    asm.add_branch(Code::Jmp_rel32_64, &end_label);
+    let mut regs_handler = RegistersHandler::new();
+    let link_reg = regs_handler.get_free()?;
 
-    asm.uw_add_with_label(Inst::with2(Code::Mov_r64_imm64, Register::RSI, addr), &call_label);
-    asm.uw_add(Inst::with2(Code::Mov_r64_imm64, Register::RDI, context_addr));
-    asm.uw_add(Inst::with1(Code::Push_r64, Register::RAX));
-    asm.uw_add(Inst::with2(Code::Mov_r64_imm64, Register::RAX, utils::get_fn_addr!(Context::branch)));
-    asm.uw_add(Inst::with1(Code::Call_rm64, Register::RAX));
-    asm.uw_add(Inst::with1(Code::Pop_r64, Register::RAX));
-    asm.add_with_label(Inst::with(Code::Nopd), &end_label);
+    asm.uw_add(Inst::with2(Code::Mov_r64_imm64, link_reg, next_pc(context)));
+    context.emit_set_reg(asm, link_reg, Reg::X30)?;
+    emit_branch_imm(context, asm, addr);
+    Ok(false)
 }
 
-pub fn emit_beq(
+pub fn emit_br(
     context: &mut Context,
     asm: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
+) -> Result<bool> {
     assert_eq!(operands.len(), 1);
-    let addr = get_label(&operands[0]);
-    cmp_and_branch(context, asm, Code::Je_rel32_64, addr);
-    false
+    let mut regs_handler = RegistersHandler::new();
+    let target_reg = regs_handler.get_free()?;
+
+    context.emit_get_reg(asm, get_reg(&operands[0])?, target_reg)?;
+    emit_branch_reg(context, asm, target_reg);
+    Ok(false)
 }
 
-pub fn emit_bne(
+pub fn emit_blr(
     context: &mut Context,
     asm: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
+) -> Result<bool> {
     assert_eq!(operands.len(), 1);
-    let addr = get_label(&operands[0]);
-    cmp_and_branch(context, asm, Code::Jne_rel32_64, addr);
-    false
+    let mut regs_handler = RegistersHandler::new();
+    let target_reg = regs_handler.get_free()?;
+    let link_reg = regs_handler.get_free()?;
+
+    // Read the target first, BLR X30 branches to the old link register
+    context.emit_get_reg(asm, get_reg(&operands[0])?, target_reg)?;
+    asm.uw_add(Inst::with2(Code::Mov_r64_imm64, link_reg, next_pc(context)));
+    context.emit_set_reg(asm, link_reg, Reg::X30)?;
+    emit_branch_reg(context, asm, target_reg);
+    Ok(false)
+}
+
+pub fn emit_ret(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    let target = match operands {
+        [] => Reg::X30,
+        [operand] => get_reg(operand)?,
+        _ => unreachable!("ret takes at most one register"),
+    };
+
+    let mut regs_handler = RegistersHandler::new();
+    let target_reg = regs_handler.get_free()?;
+
+    context.emit_get_reg(asm, target, target_reg)?;
+    emit_branch_reg(context, asm, target_reg);
+    Ok(false)
+}
+
+/// CBZ/CBNZ. W registers are zero extended on load, so testing the full
+/// register covers both widths.
+fn compare_and_branch(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    branch_if_zero: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let reg = get_reg(&operands[0])?;
+    let addr = get_label(&operands[1])?;
+
+    emit_cond_branch(context, asm, addr, |context, asm| {
+        let mut regs_handler = RegistersHandler::new();
+        let value_reg = regs_handler.get_free()?;
+
+        context.emit_get_reg(asm, reg, value_reg)?;
+        asm.uw_add(Inst::with2(Code::Test_rm64_r64, value_reg, value_reg));
+        if branch_if_zero {
+            Ok(Code::Jne_rel32_64)
+        } else {
+            Ok(Code::Je_rel32_64)
+        }
+    })?;
+    Ok(false)
+}
+
+pub fn emit_cbz(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    compare_and_branch(context, asm, operands, true)
+}
+
+pub fn emit_cbnz(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    compare_and_branch(context, asm, operands, false)
+}
+
+/// TBZ/TBNZ, testing a single bit with bt which leaves it in CF.
+fn test_and_branch(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    branch_if_zero: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 3);
+    let reg = get_reg(&operands[0])?;
+    let bit = get_imm(&operands[1])?;
+    let addr = get_label(&operands[2])?;
+
+    emit_cond_branch(context, asm, addr, |context, asm| {
+        let mut regs_handler = RegistersHandler::new();
+        let value_reg = regs_handler.get_free()?;
+
+        context.emit_get_reg(asm, reg, value_reg)?;
+        asm.uw_add(Inst::with2(Code::Bt_rm64_imm8, value_reg, bit as u32));
+        if branch_if_zero {
+            Ok(Code::Jb_rel32_64)
+        } else {
+            Ok(Code::Jae_rel32_64)
+        }
+    })?;
+    Ok(false)
+}
+
+pub fn emit_tbz(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    test_and_branch(context, asm, operands, true)
+}
+
+pub fn emit_tbnz(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    test_and_branch(context, asm, operands, false)
 }
@@ -1,105 +1,104 @@
+use crate::error::{Error, Result};
 use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
 use crate::jit::assembler::registers_handler::RegistersHandler;
-use crate::jit::context::Context;
-use bad64::{Condition, Imm, Operand, Reg};
+use crate::jit::context::{is_w_reg, Context};
+use crate::jit::emitter_arithmetic::{emit_adds, emit_subs, get_imm, get_reg};
+use crate::jit::parser::Emitter;
+use bad64::{Operand, Reg};
 use iced_x86::Code;
 
-pub fn emit_cmp(context: &mut Context, asm: &mut InstAssembler, operands: &[Operand]) -> bool {
-    assert_eq!(operands.len(), 2);
-    let left_op = &operands[0];
-    let right_op = &operands[1];
-    match left_op {
-        Operand::Reg { reg, .. } => match right_op {
-            Operand::Imm64 { imm, .. } => match imm {
-                Imm::Unsigned(imm) => {
-                    let mut regs_handler = RegistersHandler::new();
-                    let dest_register = regs_handler.get_free().unwrap();
-                    let value_reg = regs_handler.get_free().unwrap();
-
-                    context.emit_get_reg(asm, *reg, dest_register);
-                    asm.uw_add(Inst::with2(Code::Mov_r64_imm64, value_reg, *imm));
-                    asm.uw_add(Inst::with2(
-                        Code::Sub_r64_rm64,
-                        dest_register,
-                        value_reg,
-                    ));
-                    context.registers.nzcv.emit_update(asm);
-                }
-                _ => panic!("Signed imm value not supported {}", imm),
-            },
-            _ => panic!("Unknown right cmp operand {}", right_op),
+/// Turns `rn, op2` into `zr, rn, op2` so compares can reuse ADDS/SUBS.
+fn compare_operands(left_op: &Operand, right_op: &Operand) -> Result<[Operand; 3]> {
+    let zero_reg = if is_w_reg(get_reg(left_op)?) {
+        Reg::WZR
+    } else {
+        Reg::XZR
+    };
+    Ok([
+        Operand::Reg {
+            reg: zero_reg,
+            arrspec: None,
         },
-        _ => panic!("Unknown left cmp operand {}", left_op),
-    }
-    true
+        *left_op,
+        *right_op,
+    ])
 }
 
-pub fn emit_cmn(context: &mut Context, asm: &mut InstAssembler, operands: &[Operand]) -> bool {
+pub fn emit_cmp(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
     assert_eq!(operands.len(), 2);
-    let left_op = &operands[0];
-    let right_op = &operands[1];
-
-    match left_op {
-        Operand::Reg { reg, .. } => match right_op {
-            Operand::Imm64 { imm, .. } => match imm {
-                Imm::Unsigned(imm) => {
-                    let negated_imm = -(*imm as i64);
-                    let mut regs_handler = RegistersHandler::new();
-                    let dest_reg = regs_handler.get_free().unwrap();
-                    let value_reg = regs_handler.get_free().unwrap();
+    emit_subs(context, asm, &compare_operands(&operands[0], &operands[1])?)
+}
 
-                    context.emit_get_reg(asm, *reg, dest_reg);
-                    asm.uw_add(Inst::with2(
-                        Code::Mov_r64_imm64,
-                        value_reg,
-                        negated_imm,
-                    ));
-                    asm.uw_add(Inst::with2(Code::Sub_r64_rm64, dest_reg, value_reg));
-                    context.registers.nzcv.emit_update(asm);
-                }
-                _ => panic!("Signed imm value not supported {}", imm),
-            },
-            _ => panic!("Unknown right cmp operand {}", right_op),
-        },
-        _ => panic!("Unknown left cmp operand {}", left_op),
-    }
-    true
+pub fn emit_cmn(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    emit_adds(context, asm, &compare_operands(&operands[0], &operands[1])?)
 }
 
-pub fn emit_ccmn(context: &mut Context, asm: &mut InstAssembler, operands: &[Operand]) -> bool {
+/// CCMP/CCMN: compares when `cond` holds, otherwise sets NZCV to the immediate.
+fn emit_cond_compare(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    compare: Emitter,
+) -> Result<bool> {
     assert_eq!(operands.len(), 4);
-    let nzcv = match &operands[2] {
-        Operand::Imm32 { imm, .. } => match imm {
-            Imm::Unsigned(imm) => imm,
-            _ => panic!("nzcv ccmn must be unsigned"),
-        },
-        _ => panic!("nzcv ccmn must be immediate"),
-    };
+    let nzcv = get_imm(&operands[2])?;
     let cond = match &operands[3] {
-        Operand::Cond(cond) => cond,
-        _ => panic!("ccmn should hold a cond"),
+        Operand::Cond(cond) => *cond,
+        operand => return Err(Error::UnsupportedOperand(operand.to_string())),
     };
 
     let mut regs_handler = RegistersHandler::new();
-    let nzcv_reg = regs_handler.get_free().unwrap();
+    let cond_reg = regs_handler.get_free()?;
+    let temp_reg = regs_handler.get_free()?;
 
-    context.registers.nzcv.emit_get_z(asm, nzcv_reg);
-    asm.uw_add(Inst::with2(Code::Cmp_rm64_imm8, nzcv_reg, 1));
-
-    let compare_label = asm.create_label();
+    let else_label = asm.create_label();
     let end_label = asm.create_label();
 
-    match cond {
-        Condition::NE => asm.add_branch(Code::Jne_rel32_64, &compare_label),
-        _ => panic!("Unsupported condition {}", cond),
-    }
+    context.flags_tracker.emit_condition(
+        asm,
+        &mut context.registers.nzcv,
+        cond,
+        cond_reg,
+        temp_reg,
+    )?;
+    asm.uw_add(Inst::with2(Code::Test_rm64_r64, cond_reg, cond_reg));
+    asm.add_branch(Code::Je_rel32_64, &else_label);
 
-    context.registers.nzcv.emit_set(asm, (*nzcv) << 28);
+    context.register_allocator.freeze();
+    compare(context, asm, &compare_operands(&operands[0], &operands[1])?)?;
+    context.register_allocator.thaw();
     asm.add_branch(Code::Jmp_rel32_64, &end_label);
 
-    asm.add_with_label(Inst::with(Code::Nopd), &compare_label);
-    emit_cmn(context, asm, &[operands[0], operands[1]]);
+    asm.add_with_label(Inst::with(Code::Nopd), &else_label);
+    context
+        .flags_tracker
+        .emit_set(asm, &mut context.registers.nzcv, nzcv << 28);
 
     asm.add_with_label(Inst::with(Code::Nopd), &end_label);
-    true
+    Ok(true)
+}
+
+pub fn emit_ccmp(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_cond_compare(context, asm, operands, emit_subs)
+}
+
+pub fn emit_ccmn(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_cond_compare(context, asm, operands, emit_adds)
 }
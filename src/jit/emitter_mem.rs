@@ -1,44 +1,553 @@
-use crate::jit::assembler::instructions_assembler::InstAssembler;
-use crate::jit::context::Context;
-use bad64::Operand;
+use crate::error::{Error, Result};
+use crate::jit::assembler::instructions_assembler::{CallArg, Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::{
+    map_reg_16, map_reg_32, map_reg_8, RegistersHandler,
+};
+use crate::jit::context::{is_vreg, Context, ExitReason};
+use crate::jit::emitter_arithmetic::get_reg;
+use crate::jit::utils;
+use crate::memory::{LOAD_BASE, PAGE_SIZE};
+use bad64::{Imm, Operand, Reg, Shift};
+use iced_x86::{Code, MemoryOperand, Register};
 
-pub fn emit_ldp(
+/// Guest address of a memory operand, plus the new base register value for
+/// pre- and post-indexed forms.
+struct Address {
+    addr_reg: Register,
+    writeback: Option<(Reg, Register)>,
+}
+
+fn get_imm(imm: &Imm) -> i64 {
+    match imm {
+        Imm::Signed(imm) => *imm,
+        Imm::Unsigned(imm) => *imm as i64,
+    }
+}
+
+fn unsupported_size(size: usize) -> Error {
+    Error::UnsupportedOperand(format!("{}-byte access", size))
+}
+
+fn emit_add_imm(asm: &mut InstAssembler, reg: Register, imm: i64) -> Result<()> {
+    if imm != 0 {
+        let imm = i32::try_from(imm)
+            .map_err(|_| Error::UnsupportedOperand(format!("offset {:#x}", imm)))?;
+        asm.uw_add(Inst::with2(Code::Add_rm64_imm32, reg, imm));
+    }
+    Ok(())
+}
+
+fn emit_address(
     context: &mut Context,
-    assembler: &mut InstAssembler,
+    asm: &mut InstAssembler,
+    regs_handler: &mut RegistersHandler,
+    operand: &Operand,
+) -> Result<Address> {
+    let addr_reg = regs_handler.get_free()?;
+    let mut writeback = None;
+
+    match operand {
+        Operand::MemReg(reg) => context.emit_get_reg(asm, *reg, addr_reg)?,
+        Operand::MemOffset { reg, offset, .. } => {
+            context.emit_get_reg(asm, *reg, addr_reg)?;
+            emit_add_imm(asm, addr_reg, get_imm(offset))?;
+        }
+        Operand::MemPreIdx { reg, imm } => {
+            context.emit_get_reg(asm, *reg, addr_reg)?;
+            emit_add_imm(asm, addr_reg, get_imm(imm))?;
+            writeback = Some((*reg, addr_reg));
+        }
+        Operand::MemPostIdxImm { reg, imm } => {
+            let base_reg = regs_handler.get_free()?;
+            context.emit_get_reg(asm, *reg, addr_reg)?;
+            asm.uw_add(Inst::with2(Code::Mov_r64_rm64, base_reg, addr_reg));
+            emit_add_imm(asm, base_reg, get_imm(imm))?;
+            writeback = Some((*reg, base_reg));
+        }
+        Operand::MemExt { regs, shift, .. } => {
+            let index_reg = regs_handler.get_free()?;
+            context.emit_get_reg(asm, regs[0], addr_reg)?;
+            // W index registers are already zero extended, which covers UXTW
+            context.emit_get_reg(asm, regs[1], index_reg)?;
+
+            let amount = match shift {
+                None => 0,
+                Some(Shift::SXTW(amount)) => {
+                    asm.uw_add(Inst::with2(
+                        Code::Movsxd_r64_rm32,
+                        index_reg,
+                        map_reg_32(&index_reg),
+                    ));
+                    *amount
+                }
+                Some(Shift::LSL(amount))
+                | Some(Shift::UXTW(amount))
+                | Some(Shift::UXTX(amount))
+                | Some(Shift::SXTX(amount)) => *amount,
+                Some(_) => return Err(Error::UnsupportedOperand(operand.to_string())),
+            };
+            if amount != 0 {
+                asm.uw_add(Inst::with2(Code::Shl_rm64_imm8, index_reg, amount));
+            }
+            asm.uw_add(Inst::with2(Code::Add_r64_rm64, addr_reg, index_reg));
+        }
+        Operand::Label(imm) => {
+            asm.uw_add(Inst::with2(
+                Code::Mov_r64_imm64,
+                addr_reg,
+                get_imm(imm) as u64,
+            ));
+        }
+        _ => return Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+
+    Ok(Address {
+        addr_reg,
+        writeback,
+    })
+}
+
+/// Bounds checks a `size` byte access at the guest address in `addr_reg` and
+/// returns a register holding the matching host address. Out of bounds
+/// accesses report the fault and leave the block.
+fn emit_host_addr(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    regs_handler: &mut RegistersHandler,
+    addr_reg: Register,
+    size: usize,
+) -> Result<Register> {
+    let host_reg = regs_handler.get_free()?;
+    let base_reg = regs_handler.get_free()?;
+    let mapped_label = asm.create_label();
+    let limit = i32::try_from(context.memory.size() - size as u64)
+        .map_err(|_| Error::OutOfReach(LOAD_BASE + context.memory.size()))?;
+
+    asm.uw_add(Inst::with2(Code::Mov_r64_rm64, host_reg, addr_reg));
+    asm.uw_add(Inst::with2(
+        Code::Sub_rm64_imm32,
+        host_reg,
+        LOAD_BASE as i32,
+    ));
+    asm.uw_add(Inst::with2(Code::Cmp_rm64_imm32, host_reg, limit));
+    asm.add_branch(Code::Jbe_rel32_64, &mapped_label);
+
+    let args = [
+        asm.var_arg(context),
+        CallArg::Reg(addr_reg),
+        CallArg::Imm(size as u64),
+    ];
+    asm.emit_host_call(utils::get_fn_addr!(Context::memory_fault), &args, None);
+    context.emit_exit(asm, ExitReason::Fault);
+
+    asm.uw_add_with_label(
+        Inst::with2(Code::Mov_r64_imm64, base_reg, context.memory.host_base()),
+        &mapped_label,
+    );
+    asm.uw_add(Inst::with2(Code::Add_r64_rm64, host_reg, base_reg));
+    Ok(host_reg)
+}
+
+/// Ends the block after a `size` byte store at the guest address in
+/// `addr_reg` that hit a page holding translated code. The translations of
+/// the written bytes are dropped and execution continues at the next
+/// instruction, which is looked up again by the dispatcher.
+fn emit_code_write_check(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    addr_reg: Register,
+    size: usize,
+) -> Result<()> {
+    // Only the address is still live after the store
+    let mut regs_handler = RegistersHandler::new();
+    regs_handler.reserve(addr_reg);
+    let first_reg = regs_handler.get_free()?;
+    let last_reg = regs_handler.get_free()?;
+    let table_reg = regs_handler.get_free()?;
+    let unchanged_label = asm.create_label();
+
+    asm.uw_add(Inst::with2(Code::Mov_r64_rm64, first_reg, addr_reg));
+    asm.uw_add(Inst::with2(
+        Code::Sub_rm64_imm32,
+        first_reg,
+        LOAD_BASE as i32,
+    ));
+    asm.uw_add(Inst::with2(Code::Mov_r64_rm64, last_reg, first_reg));
+    asm.uw_add(Inst::with2(Code::Add_rm64_imm32, last_reg, size as i32 - 1));
+    asm.uw_add(Inst::with2(
+        Code::Shr_rm64_imm8,
+        first_reg,
+        PAGE_SIZE.trailing_zeros(),
+    ));
+    asm.uw_add(Inst::with2(
+        Code::Shr_rm64_imm8,
+        last_reg,
+        PAGE_SIZE.trailing_zeros(),
+    ));
+    asm.uw_add(Inst::with2(
+        Code::Mov_r64_imm64,
+        table_reg,
+        context.memory.code_pages_addr(),
+    ));
+    asm.uw_add(Inst::with2(
+        Code::Movzx_r32_rm8,
+        map_reg_32(&first_reg),
+        MemoryOperand::with_base_index(table_reg, first_reg),
+    ));
+    asm.uw_add(Inst::with2(
+        Code::Or_r8_rm8,
+        map_reg_8(&first_reg),
+        MemoryOperand::with_base_index(table_reg, last_reg),
+    ));
+    asm.add_branch(Code::Je_rel32_64, &unchanged_label);
+
+    // Nothing is live in caller-saved registers, the block exits right after
+    let args = [
+        asm.var_arg(context),
+        CallArg::Imm(context.translation_pc),
+        CallArg::Reg(addr_reg),
+        CallArg::Imm(size as u64),
+    ];
+    asm.emit_host_call(utils::get_fn_addr!(Context::code_write), &args, None);
+    let next_pc = context.translation_pc + 4;
+    asm.emit_set_var(next_pc, context.registers.borrow_mut_pc());
+    context.emit_exit(asm, ExitReason::Branch);
+
+    asm.add_with_label(Inst::with(Code::Nopd), &unchanged_label);
+    Ok(())
+}
+
+fn emit_writeback(context: &mut Context, asm: &mut InstAssembler, address: &Address) -> Result<()> {
+    if let Some((reg, value_reg)) = address.writeback {
+        context.emit_set_reg(asm, value_reg, reg)?;
+    }
+    Ok(())
+}
+
+/// Loads `size` bytes at `mem` into the guest register `dest`, sign or zero
+/// extending them to the register width.
+fn emit_load_reg(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    regs_handler: &mut RegistersHandler,
+    mem: MemoryOperand,
+    dest: Reg,
+    size: usize,
+    signed: bool,
+) -> Result<()> {
+    let value_reg = regs_handler.get_free()?;
+    let value_reg_32 = map_reg_32(&value_reg);
+
+    if is_vreg(dest) {
+        let xmm = Register::XMM0;
+        match size {
+            1 => {
+                asm.uw_add(Inst::with2(Code::Movzx_r32_rm8, value_reg_32, mem));
+                asm.uw_add(Inst::with2(Code::Movd_xmm_rm32, xmm, value_reg_32));
+            }
+            2 => {
+                asm.uw_add(Inst::with2(Code::Movzx_r32_rm16, value_reg_32, mem));
+                asm.uw_add(Inst::with2(Code::Movd_xmm_rm32, xmm, value_reg_32));
+            }
+            4 => asm.uw_add(Inst::with2(Code::Movd_xmm_rm32, xmm, mem)),
+            8 => asm.uw_add(Inst::with2(Code::Movq_xmm_xmmm64, xmm, mem)),
+            16 => asm.uw_add(Inst::with2(Code::Movdqu_xmm_xmmm128, xmm, mem)),
+            _ => return Err(unsupported_size(size)),
+        }
+        return context.emit_set_vreg(asm, xmm, dest);
+    }
+
+    let inst = match (size, signed) {
+        (1, false) => Inst::with2(Code::Movzx_r32_rm8, value_reg_32, mem),
+        (1, true) => Inst::with2(Code::Movsx_r64_rm8, value_reg, mem),
+        (2, false) => Inst::with2(Code::Movzx_r32_rm16, value_reg_32, mem),
+        (2, true) => Inst::with2(Code::Movsx_r64_rm16, value_reg, mem),
+        (4, false) => Inst::with2(Code::Mov_r32_rm32, value_reg_32, mem),
+        (4, true) => Inst::with2(Code::Movsxd_r64_rm32, value_reg, mem),
+        (8, _) => Inst::with2(Code::Mov_r64_rm64, value_reg, mem),
+        _ => return Err(unsupported_size(size)),
+    };
+    asm.uw_add(inst);
+    context.emit_set_reg(asm, value_reg, dest)
+}
+
+/// Stores the low `size` bytes of the guest register `src` to `mem`.
+fn emit_store_reg(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    regs_handler: &mut RegistersHandler,
+    mem: MemoryOperand,
+    src: Reg,
+    size: usize,
+) -> Result<()> {
+    if is_vreg(src) {
+        let xmm = Register::XMM0;
+        context.emit_get_vreg(asm, src, xmm)?;
+        let inst = match size {
+            1 => Inst::with3(Code::Pextrb_r32m8_xmm_imm8, mem, xmm, 0),
+            2 => Inst::with3(Code::Pextrw_r32m16_xmm_imm8, mem, xmm, 0),
+            4 => Inst::with2(Code::Movd_rm32_xmm, mem, xmm),
+            8 => Inst::with2(Code::Movq_xmmm64_xmm, mem, xmm),
+            16 => Inst::with2(Code::Movdqu_xmmm128_xmm, mem, xmm),
+            _ => return Err(unsupported_size(size)),
+        };
+        asm.uw_add(inst);
+        return Ok(());
+    }
+
+    let value_reg = regs_handler.get_free()?;
+    context.emit_get_reg(asm, src, value_reg)?;
+    let inst = match size {
+        1 => Inst::with2(Code::Mov_rm8_r8, mem, map_reg_8(&value_reg)),
+        2 => Inst::with2(Code::Mov_rm16_r16, mem, map_reg_16(&value_reg)),
+        4 => Inst::with2(Code::Mov_rm32_r32, mem, map_reg_32(&value_reg)),
+        8 => Inst::with2(Code::Mov_rm64_r64, mem, value_reg),
+        _ => return Err(unsupported_size(size)),
+    };
+    asm.uw_add(inst);
+    Ok(())
+}
+
+/// Single register load, `size` of `None` means the width of the destination.
+fn emit_load(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    size: Option<usize>,
+    signed: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let dest = get_reg(&operands[0])?;
+    let size = size.unwrap_or_else(|| dest.size());
+
+    let mut regs_handler = RegistersHandler::new();
+    let address = emit_address(context, asm, &mut regs_handler, &operands[1])?;
+    let host_reg = emit_host_addr(context, asm, &mut regs_handler, address.addr_reg, size)?;
+    let mem = MemoryOperand::with_base(host_reg);
+    emit_load_reg(context, asm, &mut regs_handler, mem, dest, size, signed)?;
+    emit_writeback(context, asm, &address)?;
+    Ok(true)
+}
+
+fn emit_store(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    size: Option<usize>,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let src = get_reg(&operands[0])?;
+    let size = size.unwrap_or_else(|| src.size());
+
+    let mut regs_handler = RegistersHandler::new();
+    let address = emit_address(context, asm, &mut regs_handler, &operands[1])?;
+    let host_reg = emit_host_addr(context, asm, &mut regs_handler, address.addr_reg, size)?;
+    let mem = MemoryOperand::with_base(host_reg);
+    emit_store_reg(context, asm, &mut regs_handler, mem, src, size)?;
+    emit_writeback(context, asm, &address)?;
+    emit_code_write_check(context, asm, address.addr_reg, size)?;
+    Ok(true)
+}
+
+fn emit_load_pair(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    signed: bool,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 3);
+    let first = get_reg(&operands[0])?;
+    let second = get_reg(&operands[1])?;
+    let size = if signed { 4 } else { first.size() };
+
+    let mut regs_handler = RegistersHandler::new();
+    let address = emit_address(context, asm, &mut regs_handler, &operands[2])?;
+    let host_reg = emit_host_addr(context, asm, &mut regs_handler, address.addr_reg, size * 2)?;
+    let first_mem = MemoryOperand::with_base(host_reg);
+    let second_mem = MemoryOperand::with_base_displ(host_reg, size as i64);
+    emit_load_reg(
+        context,
+        asm,
+        &mut regs_handler,
+        first_mem,
+        first,
+        size,
+        signed,
+    )?;
+    emit_load_reg(
+        context,
+        asm,
+        &mut regs_handler,
+        second_mem,
+        second,
+        size,
+        signed,
+    )?;
+    emit_writeback(context, asm, &address)?;
+    Ok(true)
+}
+
+fn emit_store_pair(
+    context: &mut Context,
+    asm: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
+) -> Result<bool> {
     assert_eq!(operands.len(), 3);
-    true
+    let first = get_reg(&operands[0])?;
+    let second = get_reg(&operands[1])?;
+    let size = first.size();
+
+    let mut regs_handler = RegistersHandler::new();
+    let address = emit_address(context, asm, &mut regs_handler, &operands[2])?;
+    let host_reg = emit_host_addr(context, asm, &mut regs_handler, address.addr_reg, size * 2)?;
+    let first_mem = MemoryOperand::with_base(host_reg);
+    let second_mem = MemoryOperand::with_base_displ(host_reg, size as i64);
+    emit_store_reg(context, asm, &mut regs_handler, first_mem, first, size)?;
+    emit_store_reg(context, asm, &mut regs_handler, second_mem, second, size)?;
+    emit_writeback(context, asm, &address)?;
+    emit_code_write_check(context, asm, address.addr_reg, size * 2)?;
+    Ok(true)
+}
+
+pub fn emit_ldr(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load(context, asm, operands, None, false)
+}
+
+pub fn emit_ldrb(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load(context, asm, operands, Some(1), false)
+}
+
+pub fn emit_ldrh(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load(context, asm, operands, Some(2), false)
+}
+
+pub fn emit_ldrsb(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load(context, asm, operands, Some(1), true)
+}
+
+pub fn emit_ldrsh(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load(context, asm, operands, Some(2), true)
+}
+
+pub fn emit_ldrsw(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load(context, asm, operands, Some(4), true)
+}
+
+pub fn emit_ldp(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load_pair(context, asm, operands, false)
+}
+
+pub fn emit_ldpsw(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_load_pair(context, asm, operands, true)
 }
 
 pub fn emit_mov(
     context: &mut Context,
     assembler: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
+) -> Result<bool> {
     assert_eq!(operands.len(), 2);
-    let left_op = match &operands[0] {
-        Operand::Reg { reg, .. } => reg,
-        _ => panic!("Left operand must be register"),
-    };
-    let right_op = match &operands[1] {
-        Operand::Reg { reg, .. } => reg,
-        _ => panic!("Right operand must be register"),
-    };
-    //let right_reg_addr = context.registers.map_addr(*right_op);
-    /*emit::asm!(assembler
-        ;; emit::get_reg!(context, assembler, *left_op, rax)
-        ; mov rcx, QWORD right_reg_addr as _
-        ; mov [rcx], rax
-    );*/
-    true
+    // Only whole registers are copied, lanes and arrangements would need
+    // INS/DUP/UMOV and are left to the interpreter
+    let lane_operand = operands.iter().find(|operand| {
+        matches!(
+            operand,
+            Operand::Reg {
+                arrspec: Some(_),
+                ..
+            }
+        )
+    });
+    if let Some(operand) = lane_operand {
+        return Err(Error::UnsupportedOperand(operand.to_string()));
+    }
+    let dest_reg = get_reg(&operands[0])?;
+
+    if is_vreg(dest_reg) {
+        let src_reg = get_reg(&operands[1])?;
+        context.emit_get_vreg(assembler, src_reg, Register::XMM0)?;
+        context.emit_set_vreg(assembler, Register::XMM0, dest_reg)?;
+        return Ok(true);
+    }
+
+    let mut regs_handler = RegistersHandler::new();
+    let value_reg = regs_handler.get_free()?;
+
+    match &operands[1] {
+        Operand::Reg { reg, .. } => context.emit_get_reg(assembler, *reg, value_reg)?,
+        Operand::Imm32 { imm, .. } | Operand::Imm64 { imm, .. } => {
+            let imm = match imm {
+                Imm::Unsigned(imm) => *imm,
+                Imm::Signed(imm) => *imm as u64,
+            };
+            assembler.uw_add(Inst::with2(Code::Mov_r64_imm64, value_reg, imm));
+        }
+        operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+    context.emit_set_reg(assembler, value_reg, dest_reg)?;
+    Ok(true)
 }
 
 pub fn emit_str(
     context: &mut Context,
-    assembler: &mut InstAssembler,
+    asm: &mut InstAssembler,
     operands: &[Operand],
-) -> bool {
-    assert_eq!(operands.len(), 2);
-    true
+) -> Result<bool> {
+    emit_store(context, asm, operands, None)
+}
+
+pub fn emit_strb(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_store(context, asm, operands, Some(1))
+}
+
+pub fn emit_strh(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_store(context, asm, operands, Some(2))
+}
+
+pub fn emit_stp(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_store_pair(context, asm, operands)
 }
@@ -0,0 +1,172 @@
+use crate::error::{Error, Result};
+use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::RegistersHandler;
+use crate::jit::context::{invert_condition, is_w_reg, Context};
+use crate::jit::emitter_arithmetic::{get_reg, op_reg};
+use bad64::{Condition, Operand, Reg};
+use iced_x86::Code;
+
+/// What the conditional select applies to its second source when `cond` fails.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum SelectOp {
+    None,
+    Inc,
+    Inv,
+    Neg,
+}
+
+fn get_cond(operand: &Operand) -> Result<Condition> {
+    match operand {
+        Operand::Cond(cond) => Ok(*cond),
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+}
+
+fn emit_select(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+    op: SelectOp,
+) -> Result<bool> {
+    assert_eq!(operands.len(), 4);
+    let dest = get_reg(&operands[0])?;
+    let cond = get_cond(&operands[3])?;
+    let is_32bit = is_w_reg(dest);
+
+    let mut regs_handler = RegistersHandler::new();
+    let cond_reg = regs_handler.get_free()?;
+    let temp_reg = regs_handler.get_free()?;
+    let true_reg = regs_handler.get_free()?;
+    let false_reg = regs_handler.get_free()?;
+
+    context.flags_tracker.emit_condition(
+        asm,
+        &mut context.registers.nzcv,
+        cond,
+        cond_reg,
+        temp_reg,
+    )?;
+    context.emit_get_reg(asm, get_reg(&operands[1])?, true_reg)?;
+    context.emit_get_reg(asm, get_reg(&operands[2])?, false_reg)?;
+
+    let false_op = op_reg(false_reg, is_32bit);
+    match (op, is_32bit) {
+        (SelectOp::None, _) => {}
+        (SelectOp::Inc, false) => asm.uw_add(Inst::with2(Code::Add_rm64_imm8, false_op, 1)),
+        (SelectOp::Inc, true) => asm.uw_add(Inst::with2(Code::Add_rm32_imm8, false_op, 1)),
+        (SelectOp::Inv, false) => asm.uw_add(Inst::with1(Code::Not_rm64, false_op)),
+        (SelectOp::Inv, true) => asm.uw_add(Inst::with1(Code::Not_rm32, false_op)),
+        (SelectOp::Neg, false) => asm.uw_add(Inst::with1(Code::Neg_rm64, false_op)),
+        (SelectOp::Neg, true) => asm.uw_add(Inst::with1(Code::Neg_rm32, false_op)),
+    }
+
+    asm.uw_add(Inst::with2(Code::Test_rm64_r64, cond_reg, cond_reg));
+    let cmov = if is_32bit {
+        Code::Cmovne_r32_rm32
+    } else {
+        Code::Cmovne_r64_rm64
+    };
+    asm.uw_add(Inst::with2(cmov, false_op, op_reg(true_reg, is_32bit)));
+
+    context.emit_set_reg(asm, false_reg, dest)?;
+    Ok(true)
+}
+
+/// Expands the CSET/CSETM/CINC/CINV/CNEG aliases, `rd, [rn,] cond`, into
+/// `rd, rn, rn, invert(cond)`, with the zero register when `rn` is absent.
+fn alias_operands(operands: &[Operand]) -> Result<[Operand; 4]> {
+    let (source, cond) = match operands {
+        [dest, cond] => {
+            let zero_reg = if is_w_reg(get_reg(dest)?) {
+                Reg::WZR
+            } else {
+                Reg::XZR
+            };
+            let zero_op = Operand::Reg {
+                reg: zero_reg,
+                arrspec: None,
+            };
+            (zero_op, cond)
+        }
+        [_, source, cond] => (*source, cond),
+        _ => unreachable!("Conditional select aliases take two or three operands"),
+    };
+    Ok([
+        operands[0],
+        source,
+        source,
+        Operand::Cond(invert_condition(get_cond(cond)?)),
+    ])
+}
+
+pub fn emit_csel(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_select(context, asm, operands, SelectOp::None)
+}
+
+pub fn emit_csinc(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_select(context, asm, operands, SelectOp::Inc)
+}
+
+pub fn emit_csinv(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_select(context, asm, operands, SelectOp::Inv)
+}
+
+pub fn emit_csneg(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_select(context, asm, operands, SelectOp::Neg)
+}
+
+pub fn emit_cset(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_csinc(context, asm, &alias_operands(operands)?)
+}
+
+pub fn emit_csetm(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_csinv(context, asm, &alias_operands(operands)?)
+}
+
+pub fn emit_cinc(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_csinc(context, asm, &alias_operands(operands)?)
+}
+
+pub fn emit_cinv(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_csinv(context, asm, &alias_operands(operands)?)
+}
+
+pub fn emit_cneg(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    emit_csneg(context, asm, &alias_operands(operands)?)
+}
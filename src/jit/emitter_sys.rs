@@ -0,0 +1,88 @@
+use crate::error::{Error, Result};
+use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::{map_reg_32, RegistersHandler};
+use crate::jit::context::{Context, NZCV_MASK};
+use crate::jit::emitter_arithmetic::get_reg;
+use bad64::{Operand, SysReg};
+use iced_x86::Code;
+
+fn get_sys_reg(operand: &Operand) -> Result<SysReg> {
+    match operand {
+        Operand::SysReg(sys_reg) => Ok(*sys_reg),
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+}
+
+/// Whether the system register operand is one the emitters below handle, the
+/// others are left to the interpreter.
+pub fn known_sys_reg(operands: &[Operand]) -> bool {
+    operands.iter().any(|operand| {
+        matches!(
+            operand,
+            Operand::SysReg(SysReg::NZCV | SysReg::FPCR | SysReg::FPSR)
+        )
+    })
+}
+
+pub fn emit_mrs(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let dest_reg = get_reg(&operands[0])?;
+    let sys_reg = get_sys_reg(&operands[1])?;
+
+    let mut regs_handler = RegistersHandler::new();
+    let value_reg = regs_handler.get_free()?;
+
+    match sys_reg {
+        SysReg::NZCV => {
+            let temp_reg = regs_handler.get_free()?;
+            context.flags_tracker.emit_materialize(
+                asm,
+                &mut context.registers.nzcv,
+                value_reg,
+                temp_reg,
+            );
+            asm.emit_var_to_reg(context.registers.nzcv.borrow_mut_value(), value_reg);
+        }
+        SysReg::FPCR => asm.emit_var_to_reg(context.registers.borrow_mut_fpcr(), value_reg),
+        SysReg::FPSR => asm.emit_var_to_reg(context.registers.borrow_mut_fpsr(), value_reg),
+        _ => return Err(Error::UnsupportedOperand(sys_reg.to_string())),
+    }
+    context.emit_set_reg(asm, value_reg, dest_reg)?;
+    Ok(true)
+}
+
+pub fn emit_msr(
+    context: &mut Context,
+    asm: &mut InstAssembler,
+    operands: &[Operand],
+) -> Result<bool> {
+    assert_eq!(operands.len(), 2);
+    let sys_reg = get_sys_reg(&operands[0])?;
+    let src_reg = get_reg(&operands[1])?;
+
+    let mut regs_handler = RegistersHandler::new();
+    let value_reg = regs_handler.get_free()?;
+
+    context.emit_get_reg(asm, src_reg, value_reg)?;
+    match sys_reg {
+        SysReg::NZCV => {
+            // Bits other than NZCV are RES0, 32-bit AND clears the upper half
+            asm.uw_add(Inst::with2(
+                Code::And_rm32_imm32,
+                map_reg_32(&value_reg),
+                NZCV_MASK as u32,
+            ));
+            context
+                .flags_tracker
+                .emit_set_reg(asm, &mut context.registers.nzcv, value_reg);
+        }
+        SysReg::FPCR => asm.emit_set_var(value_reg, context.registers.borrow_mut_fpcr()),
+        SysReg::FPSR => asm.emit_set_var(value_reg, context.registers.borrow_mut_fpsr()),
+        _ => return Err(Error::UnsupportedOperand(sys_reg.to_string())),
+    }
+    Ok(true)
+}
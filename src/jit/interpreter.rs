@@ -0,0 +1,871 @@
+use crate::error::{Error, Result};
+use crate::jit::context::{
+    add_with_carry, condition_holds, invert_condition, is_gpr, is_vreg, is_w_reg, is_zero_reg,
+    Registers, NZCV_MASK,
+};
+use crate::jit::emitter_arithmetic::{get_imm, get_reg};
+use crate::logging::log;
+use crate::memory::GuestMemory;
+use bad64::{ArrSpec, Condition, Imm, Instruction, Op, Operand, Reg, Shift, SysReg};
+use std::ops::Range;
+
+/// Guest memory written by an interpreted instruction, along with the bytes
+/// it replaced.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub addr: u64,
+    pub previous: Vec<u8>,
+}
+
+impl MemoryWrite {
+    pub fn range(&self) -> Range<u64> {
+        self.addr..self.addr + self.previous.len() as u64
+    }
+}
+
+/// Executes the instruction at the guest PC and advances the PC. Returns the
+/// guest memory it wrote, if any. A failing instruction changes nothing and
+/// leaves the PC pointing at it, the error carries the instruction.
+///
+/// The interpreter covers every op the JIT translates and a few more. Blocks
+/// end right before an instruction without an emitter and leave it to this.
+pub fn step(registers: &mut Registers, memory: &mut GuestMemory) -> Result<Option<MemoryWrite>> {
+    let pc = *registers.borrow_mut_pc();
+    let word = memory
+        .read_u32(pc)
+        .map_err(|fault| Error::from(fault).at(pc, memory))?;
+    let inst = match bad64::decode(word, pc) {
+        Ok(inst) => inst,
+        Err(_) => return Err(Error::UndefinedInstruction.at(pc, memory)),
+    };
+
+    let mut interpreter = Interpreter {
+        registers,
+        memory,
+        next_pc: pc + 4,
+        write: None,
+    };
+    interpreter
+        .execute(&inst)
+        .map_err(|error| error.at(pc, interpreter.memory))?;
+    *interpreter.registers.borrow_mut_pc() = interpreter.next_pc;
+    Ok(interpreter.write)
+}
+
+struct Interpreter<'a> {
+    registers: &'a mut Registers,
+    memory: &'a mut GuestMemory,
+    /// Where execution continues, the next instruction unless a branch is taken
+    next_pc: u64,
+    write: Option<MemoryWrite>,
+}
+
+fn width(is_32bit: bool) -> u32 {
+    if is_32bit {
+        32
+    } else {
+        64
+    }
+}
+
+/// Truncates `value` to the width of the guest operation.
+fn mask(value: u64, is_32bit: bool) -> u64 {
+    if is_32bit {
+        value & 0xFFFFFFFF
+    } else {
+        value
+    }
+}
+
+/// Bits below `bits` set, `bits` may be up to 64.
+fn low_bits(bits: u32) -> u64 {
+    u64::MAX.checked_shr(64 - bits).unwrap_or(0)
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    ((value << (64 - bits)) as i64 >> (64 - bits)) as u64
+}
+
+fn imm_value(imm: &Imm) -> u64 {
+    match imm {
+        Imm::Unsigned(imm) => *imm,
+        Imm::Signed(imm) => *imm as u64,
+    }
+}
+
+fn get_label(operand: &Operand) -> Result<u64> {
+    match operand {
+        Operand::Label(imm) => Ok(imm_value(imm)),
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+}
+
+fn get_cond(operand: &Operand) -> Result<Condition> {
+    match operand {
+        Operand::Cond(cond) => Ok(*cond),
+        _ => Err(Error::UnsupportedOperand(operand.to_string())),
+    }
+}
+
+/// Applies a shift or extension of a register operand, like `emit_operand`.
+fn apply_shift(value: u64, shift: Shift, is_32bit: bool) -> Result<u64> {
+    let bits = width(is_32bit);
+    let value = match shift {
+        Shift::LSL(amount) => value << amount,
+        Shift::LSR(amount) => mask(value, is_32bit) >> amount,
+        Shift::ASR(amount) => (sign_extend(value, bits) as i64 >> amount) as u64,
+        Shift::ROR(amount) => rotate_right(value, amount, is_32bit),
+        Shift::UXTB(amount) => (value & 0xFF) << amount,
+        Shift::UXTH(amount) => (value & 0xFFFF) << amount,
+        Shift::UXTW(amount) => (value & 0xFFFFFFFF) << amount,
+        Shift::SXTB(amount) => sign_extend(value, 8) << amount,
+        Shift::SXTH(amount) => sign_extend(value, 16) << amount,
+        Shift::SXTW(amount) => sign_extend(value, 32) << amount,
+        Shift::UXTX(amount) | Shift::SXTX(amount) => value << amount,
+        Shift::MSL(_) => return Err(Error::UnsupportedOperand(shift.to_string())),
+    };
+    Ok(mask(value, is_32bit))
+}
+
+fn rotate_right(value: u64, amount: u32, is_32bit: bool) -> u64 {
+    if is_32bit {
+        (value as u32).rotate_right(amount) as u64
+    } else {
+        value.rotate_right(amount)
+    }
+}
+
+/// NZCV of a logical operation, C and V are cleared.
+fn logical_flags(result: u64, is_32bit: bool) -> u64 {
+    add_with_carry(result, 0, false, is_32bit).1
+}
+
+fn branch_condition(op: Op) -> Option<Condition> {
+    let cond = match op {
+        Op::B_EQ => Condition::EQ,
+        Op::B_NE => Condition::NE,
+        Op::B_CS => Condition::CS,
+        Op::B_CC => Condition::CC,
+        Op::B_MI => Condition::MI,
+        Op::B_PL => Condition::PL,
+        Op::B_VS => Condition::VS,
+        Op::B_VC => Condition::VC,
+        Op::B_HI => Condition::HI,
+        Op::B_LS => Condition::LS,
+        Op::B_GE => Condition::GE,
+        Op::B_LT => Condition::LT,
+        Op::B_GT => Condition::GT,
+        Op::B_LE => Condition::LE,
+        Op::B_AL => Condition::AL,
+        Op::B_NV => Condition::NV,
+        _ => return None,
+    };
+    Some(cond)
+}
+
+/// Fails on register operands `inst` can't take. Only moves, loads and stores
+/// handle SIMD/FP registers, and only moves their lanes and arrangements.
+fn check_registers(inst: &Instruction) -> Result<()> {
+    let is_move = matches!(inst.op(), Op::MOV | Op::FMOV);
+    let is_load_store = matches!(
+        inst.op(),
+        Op::LDR | Op::LDUR | Op::LDP | Op::LDNP | Op::STR | Op::STUR | Op::STP | Op::STNP
+    );
+    for operand in inst.operands() {
+        let supported = match operand {
+            Operand::Reg { reg, arrspec } => {
+                is_gpr(*reg)
+                    || is_zero_reg(*reg)
+                    || is_vreg(*reg) && (is_move || is_load_store && arrspec.is_none())
+            }
+            Operand::ShiftReg { reg, .. } => is_gpr(*reg) || is_zero_reg(*reg),
+            Operand::MultiReg { .. } | Operand::IndexedElement { .. } => false,
+            _ => true,
+        };
+        if !supported {
+            return Err(Error::UnsupportedOperand(operand.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Size of a single element of `arrspec`.
+fn element_size(arrspec: ArrSpec) -> usize {
+    match arrspec {
+        ArrSpec::Full(_) => 16,
+        ArrSpec::TwoDoubles(_) | ArrSpec::OneDouble(_) => 8,
+        ArrSpec::FourSingles(_) | ArrSpec::TwoSingles(_) | ArrSpec::OneSingle(_) => 4,
+        ArrSpec::EightHalves(_)
+        | ArrSpec::FourHalves(_)
+        | ArrSpec::TwoHalves(_)
+        | ArrSpec::OneHalf(_) => 2,
+        ArrSpec::SixteenBytes(_)
+        | ArrSpec::EightBytes(_)
+        | ArrSpec::FourBytes(_)
+        | ArrSpec::OneByte(_) => 1,
+    }
+}
+
+/// Bytes of a SIMD/FP register an operand covers: one lane, an arrangement
+/// or the whole view.
+fn vector_bytes(reg: Reg, arrspec: Option<ArrSpec>) -> Range<usize> {
+    let arrspec = match arrspec {
+        Some(arrspec) => arrspec,
+        None => return 0..reg.size(),
+    };
+    let size = element_size(arrspec);
+    match arrspec.lane() {
+        Some(lane) => lane as usize * size..(lane as usize + 1) * size,
+        None => {
+            let elements = match arrspec {
+                ArrSpec::Full(_)
+                | ArrSpec::OneDouble(_)
+                | ArrSpec::OneSingle(_)
+                | ArrSpec::OneHalf(_)
+                | ArrSpec::OneByte(_) => 1,
+                ArrSpec::TwoDoubles(_) | ArrSpec::TwoSingles(_) | ArrSpec::TwoHalves(_) => 2,
+                ArrSpec::FourSingles(_) | ArrSpec::FourHalves(_) | ArrSpec::FourBytes(_) => 4,
+                ArrSpec::EightHalves(_) | ArrSpec::EightBytes(_) => 8,
+                ArrSpec::SixteenBytes(_) => 16,
+            };
+            0..size * elements
+        }
+    }
+}
+
+/// Zero extends the little-endian `bytes`.
+fn from_bytes(bytes: &[u8]) -> u128 {
+    let mut buf = [0u8; 16];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    u128::from_le_bytes(buf)
+}
+
+impl Interpreter<'_> {
+    /// Reads a general-purpose register. W registers are zero extended and
+    /// the zero registers read as 0.
+    fn reg(&mut self, reg: Reg) -> Result<u64> {
+        if is_zero_reg(reg) {
+            return Ok(0);
+        }
+        let value = *self.registers.borrow_mut_reg(reg)?;
+        Ok(mask(value, is_w_reg(reg)))
+    }
+
+    /// Writes a general-purpose register. W writes zero the upper half and
+    /// writes to the zero registers are discarded.
+    fn set_reg(&mut self, reg: Reg, value: u64) -> Result<()> {
+        if !is_zero_reg(reg) {
+            *self.registers.borrow_mut_reg(reg)? = mask(value, is_w_reg(reg));
+        }
+        Ok(())
+    }
+
+    /// Reads the `reg.size()` low bytes of a SIMD/FP register.
+    fn vreg(&mut self, reg: Reg) -> Result<u128> {
+        let value = *self.registers.borrow_mut_vreg(reg)?;
+        Ok(from_bytes(&value.to_le_bytes()[..reg.size()]))
+    }
+
+    /// Writes a SIMD/FP register, clearing the bytes beyond the view.
+    fn set_vreg(&mut self, reg: Reg, value: u128) -> Result<()> {
+        *self.registers.borrow_mut_vreg(reg)? = from_bytes(&value.to_le_bytes()[..reg.size()]);
+        Ok(())
+    }
+
+    fn nzcv(&mut self) -> Result<u64> {
+        self.registers.nzcv.value()
+    }
+
+    fn carry(&mut self) -> Result<bool> {
+        Ok((self.nzcv()? >> 29) & 1 != 0)
+    }
+
+    /// The flexible second operand: an immediate, a plain register, a shifted
+    /// register or an extended register.
+    fn operand(&mut self, operand: &Operand, is_32bit: bool) -> Result<u64> {
+        match operand {
+            Operand::Imm32 { .. } | Operand::Imm64 { .. } => Ok(mask(get_imm(operand)?, is_32bit)),
+            Operand::Reg { reg, .. } => Ok(self.reg(*reg)?),
+            Operand::ShiftReg { reg, shift } => {
+                let value = self.reg(*reg)?;
+                apply_shift(value, *shift, is_32bit)
+            }
+            _ => Err(Error::UnsupportedOperand(operand.to_string())),
+        }
+    }
+
+    fn execute(&mut self, inst: &Instruction) -> Result<()> {
+        let operands = inst.operands();
+        check_registers(inst)?;
+        if let Some(cond) = branch_condition(inst.op()) {
+            let addr = get_label(&operands[0])?;
+            if condition_holds(cond, self.nzcv()?) {
+                self.next_pc = addr;
+            }
+            return Ok(());
+        }
+
+        match inst.op() {
+            Op::NOP => {}
+            Op::ADD => self.add_sub(operands, false, false)?,
+            Op::ADDS => self.add_sub(operands, false, true)?,
+            Op::SUB => self.add_sub(operands, true, false)?,
+            Op::SUBS => self.add_sub(operands, true, true)?,
+            Op::NEG | Op::NEGS => {
+                let dest = get_reg(&operands[0])?;
+                let is_32bit = is_w_reg(dest);
+                let right = self.operand(&operands[1], is_32bit)?;
+                let set_flags = inst.op() == Op::NEGS;
+                self.add_with_carry(dest, 0, !right, true, set_flags)?;
+            }
+            Op::CMP => self.compare(operands, true)?,
+            Op::CMN => self.compare(operands, false)?,
+            Op::ADC | Op::ADCS | Op::SBC | Op::SBCS => {
+                let dest = get_reg(&operands[0])?;
+                let left = self.reg(get_reg(&operands[1])?)?;
+                let right = self.reg(get_reg(&operands[2])?)?;
+                let carry = self.carry()?;
+                let right = if matches!(inst.op(), Op::SBC | Op::SBCS) {
+                    !right
+                } else {
+                    right
+                };
+                let set_flags = matches!(inst.op(), Op::ADCS | Op::SBCS);
+                self.add_with_carry(dest, left, right, carry, set_flags)?;
+            }
+            Op::ADR | Op::ADRP => {
+                let addr = get_label(&operands[1])?;
+                self.set_reg(get_reg(&operands[0])?, addr)?;
+            }
+            //
+            Op::AND => self.logical(operands, |l, r| l & r, false)?,
+            Op::ANDS => self.logical(operands, |l, r| l & r, true)?,
+            Op::BIC => self.logical(operands, |l, r| l & !r, false)?,
+            Op::BICS => self.logical(operands, |l, r| l & !r, true)?,
+            Op::ORR => self.logical(operands, |l, r| l | r, false)?,
+            Op::ORN => self.logical(operands, |l, r| l | !r, false)?,
+            Op::EOR => self.logical(operands, |l, r| l ^ r, false)?,
+            Op::EON => self.logical(operands, |l, r| l ^ !r, false)?,
+            Op::TST => {
+                let is_32bit = is_w_reg(get_reg(&operands[0])?);
+                let left = self.reg(get_reg(&operands[0])?)?;
+                let right = self.operand(&operands[1], is_32bit)?;
+                let flags = logical_flags(left & right, is_32bit);
+                self.registers.nzcv.set(flags);
+            }
+            Op::MVN => {
+                let dest = get_reg(&operands[0])?;
+                let value = self.operand(&operands[1], is_w_reg(dest))?;
+                self.set_reg(dest, !value)?;
+            }
+            //
+            Op::MOV => self.mov(operands)?,
+            Op::MOVZ => {
+                let dest = get_reg(&operands[0])?;
+                self.set_reg(dest, get_imm(&operands[1])?)?;
+            }
+            Op::MOVN => {
+                let dest = get_reg(&operands[0])?;
+                self.set_reg(dest, !get_imm(&operands[1])?)?;
+            }
+            Op::MOVK => {
+                let dest = get_reg(&operands[0])?;
+                let (imm, amount) = match &operands[1] {
+                    Operand::Imm32 { imm, shift } | Operand::Imm64 { imm, shift } => {
+                        let amount = match shift {
+                            None => 0,
+                            Some(Shift::LSL(amount)) => *amount,
+                            Some(shift) => {
+                                return Err(Error::UnsupportedOperand(shift.to_string()))
+                            }
+                        };
+                        (imm_value(imm), amount)
+                    }
+                    operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+                };
+                let value = self.reg(dest)? & !(0xFFFF << amount) | (imm & 0xFFFF) << amount;
+                self.set_reg(dest, value)?;
+            }
+            //
+            Op::MADD | Op::MSUB | Op::MUL | Op::MNEG => {
+                let dest = get_reg(&operands[0])?;
+                let left = self.reg(get_reg(&operands[1])?)?;
+                let right = self.reg(get_reg(&operands[2])?)?;
+                let acc = match operands.get(3) {
+                    Some(operand) => self.reg(get_reg(operand)?)?,
+                    None => 0,
+                };
+                let product = left.wrapping_mul(right);
+                let value = match inst.op() {
+                    Op::MSUB | Op::MNEG => acc.wrapping_sub(product),
+                    _ => acc.wrapping_add(product),
+                };
+                self.set_reg(dest, value)?;
+            }
+            Op::SMADDL | Op::SMSUBL | Op::SMULL | Op::UMADDL | Op::UMSUBL | Op::UMULL => {
+                let signed = matches!(inst.op(), Op::SMADDL | Op::SMSUBL | Op::SMULL);
+                let extend = |value: u64| {
+                    if signed {
+                        sign_extend(value, 32)
+                    } else {
+                        value
+                    }
+                };
+                let dest = get_reg(&operands[0])?;
+                let left = extend(self.reg(get_reg(&operands[1])?)?);
+                let right = extend(self.reg(get_reg(&operands[2])?)?);
+                let acc = match operands.get(3) {
+                    Some(operand) => self.reg(get_reg(operand)?)?,
+                    None => 0,
+                };
+                let product = left.wrapping_mul(right);
+                let value = match inst.op() {
+                    Op::SMSUBL | Op::UMSUBL => acc.wrapping_sub(product),
+                    _ => acc.wrapping_add(product),
+                };
+                self.set_reg(dest, value)?;
+            }
+            Op::SMULH | Op::UMULH => {
+                let dest = get_reg(&operands[0])?;
+                let left = self.reg(get_reg(&operands[1])?)?;
+                let right = self.reg(get_reg(&operands[2])?)?;
+                let value = if inst.op() == Op::SMULH {
+                    ((left as i64 as i128 * right as i64 as i128) >> 64) as u64
+                } else {
+                    ((left as u128 * right as u128) >> 64) as u64
+                };
+                self.set_reg(dest, value)?;
+            }
+            Op::UDIV | Op::SDIV => {
+                let dest = get_reg(&operands[0])?;
+                let is_32bit = is_w_reg(dest);
+                let left = self.reg(get_reg(&operands[1])?)?;
+                let right = self.reg(get_reg(&operands[2])?)?;
+                // Division by zero yields 0 and the overflowing signed division
+                // wraps, neither traps
+                let value = if right == 0 {
+                    0
+                } else if inst.op() == Op::UDIV {
+                    left / right
+                } else {
+                    let bits = width(is_32bit);
+                    let left = sign_extend(left, bits) as i64;
+                    let right = sign_extend(right, bits) as i64;
+                    left.wrapping_div(right) as u64
+                };
+                self.set_reg(dest, value)?;
+            }
+            //
+            Op::LSL | Op::LSR | Op::ASR | Op::ROR => {
+                let dest = get_reg(&operands[0])?;
+                let is_32bit = is_w_reg(dest);
+                let value = self.reg(get_reg(&operands[1])?)?;
+                let amount = self.operand(&operands[2], is_32bit)? as u32 % width(is_32bit);
+                let shift = match inst.op() {
+                    Op::LSL => Shift::LSL(amount),
+                    Op::LSR => Shift::LSR(amount),
+                    Op::ASR => Shift::ASR(amount),
+                    _ => Shift::ROR(amount),
+                };
+                self.set_reg(dest, apply_shift(value, shift, is_32bit)?)?;
+            }
+            Op::UBFX | Op::SBFX | Op::UBFIZ | Op::SBFIZ | Op::BFI | Op::BFXIL => {
+                self.bitfield(inst.op(), operands)?
+            }
+            Op::UXTB | Op::UXTH | Op::SXTB | Op::SXTH | Op::SXTW => {
+                let dest = get_reg(&operands[0])?;
+                let value = self.reg(get_reg(&operands[1])?)?;
+                let shift = match inst.op() {
+                    Op::UXTB => Shift::UXTB(0),
+                    Op::UXTH => Shift::UXTH(0),
+                    Op::SXTB => Shift::SXTB(0),
+                    Op::SXTH => Shift::SXTH(0),
+                    _ => Shift::SXTW(0),
+                };
+                self.set_reg(dest, apply_shift(value, shift, is_w_reg(dest))?)?;
+            }
+            Op::CLZ | Op::RBIT | Op::REV | Op::REV16 | Op::REV32 => {
+                let dest = get_reg(&operands[0])?;
+                let is_32bit = is_w_reg(dest);
+                let value = self.reg(get_reg(&operands[1])?)?;
+                let value = match (inst.op(), is_32bit) {
+                    (Op::CLZ, true) => (value as u32).leading_zeros() as u64,
+                    (Op::CLZ, false) => value.leading_zeros() as u64,
+                    (Op::RBIT, true) => (value as u32).reverse_bits() as u64,
+                    (Op::RBIT, false) => value.reverse_bits(),
+                    (Op::REV, true) => (value as u32).swap_bytes() as u64,
+                    (Op::REV, false) => value.swap_bytes(),
+                    // Byte swaps within each halfword or word
+                    (Op::REV16, _) => {
+                        (value & 0x00FF00FF00FF00FF) << 8 | (value >> 8) & 0x00FF00FF00FF00FF
+                    }
+                    _ => {
+                        let low = (value as u32).swap_bytes() as u64;
+                        let high = ((value >> 32) as u32).swap_bytes() as u64;
+                        high << 32 | low
+                    }
+                };
+                self.set_reg(dest, value)?;
+            }
+            //
+            Op::B => self.next_pc = get_label(&operands[0])?,
+            Op::BL => {
+                self.set_reg(Reg::X30, self.next_pc)?;
+                self.next_pc = get_label(&operands[0])?;
+            }
+            Op::BR => self.next_pc = self.reg(get_reg(&operands[0])?)?,
+            Op::BLR => {
+                // Read the target first, BLR X30 branches to the old link register
+                let target = self.reg(get_reg(&operands[0])?)?;
+                self.set_reg(Reg::X30, self.next_pc)?;
+                self.next_pc = target;
+            }
+            Op::RET => {
+                let target = match operands {
+                    [] => Reg::X30,
+                    [operand] => get_reg(operand)?,
+                    _ => unreachable!("ret takes at most one register"),
+                };
+                self.next_pc = self.reg(target)?;
+            }
+            Op::CBZ | Op::CBNZ => {
+                let value = self.reg(get_reg(&operands[0])?)?;
+                if (value == 0) == (inst.op() == Op::CBZ) {
+                    self.next_pc = get_label(&operands[1])?;
+                }
+            }
+            Op::TBZ | Op::TBNZ => {
+                let value = self.reg(get_reg(&operands[0])?)?;
+                let bit = (value >> get_imm(&operands[1])?) & 1;
+                if (bit == 0) == (inst.op() == Op::TBZ) {
+                    self.next_pc = get_label(&operands[2])?;
+                }
+            }
+            //
+            Op::CCMP | Op::CCMN => {
+                let cond = get_cond(&operands[3])?;
+                if condition_holds(cond, self.nzcv()?) {
+                    self.compare(&operands[..2], inst.op() == Op::CCMP)?;
+                } else {
+                    self.registers.nzcv.set(get_imm(&operands[2])? << 28);
+                }
+            }
+            Op::CSEL => self.select(operands, |value| value)?,
+            Op::CSINC | Op::CSET | Op::CINC => {
+                self.select(operands, |value| value.wrapping_add(1))?
+            }
+            Op::CSINV | Op::CSETM | Op::CINV => self.select(operands, |value| !value)?,
+            Op::CSNEG | Op::CNEG => self.select(operands, |value| value.wrapping_neg())?,
+            //
+            Op::LDR | Op::LDUR => self.load(operands, None, false)?,
+            Op::LDRB | Op::LDURB => self.load(operands, Some(1), false)?,
+            Op::LDRH | Op::LDURH => self.load(operands, Some(2), false)?,
+            Op::LDRSB | Op::LDURSB => self.load(operands, Some(1), true)?,
+            Op::LDRSH | Op::LDURSH => self.load(operands, Some(2), true)?,
+            Op::LDRSW | Op::LDURSW => self.load(operands, Some(4), true)?,
+            Op::LDP | Op::LDNP => self.load_pair(operands, false)?,
+            Op::LDPSW => self.load_pair(operands, true)?,
+            Op::STR | Op::STUR => self.store(operands, None)?,
+            Op::STRB | Op::STURB => self.store(operands, Some(1))?,
+            Op::STRH | Op::STURH => self.store(operands, Some(2))?,
+            Op::STP | Op::STNP => self.store_pair(operands)?,
+            //
+            Op::FMOV => self.mov(operands)?,
+            Op::MRS => {
+                let value = match &operands[1] {
+                    Operand::SysReg(SysReg::NZCV) => self.nzcv()?,
+                    Operand::SysReg(SysReg::FPCR) => *self.registers.borrow_mut_fpcr(),
+                    Operand::SysReg(SysReg::FPSR) => *self.registers.borrow_mut_fpsr(),
+                    operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+                };
+                self.set_reg(get_reg(&operands[0])?, value)?;
+            }
+            Op::MSR => {
+                let value = self.reg(get_reg(&operands[1])?)?;
+                match &operands[0] {
+                    Operand::SysReg(SysReg::NZCV) => self.registers.nzcv.set(value & NZCV_MASK),
+                    Operand::SysReg(SysReg::FPCR) => *self.registers.borrow_mut_fpcr() = value,
+                    Operand::SysReg(SysReg::FPSR) => *self.registers.borrow_mut_fpsr() = value,
+                    operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+                }
+            }
+            Op::SVC => {
+                let imm = get_imm(&operands[0])?;
+                log!(Svc, Info, "svc {:#x} at {:#x}", imm, self.next_pc - 4);
+                // There is no kernel to service it
+                return Err(Error::UnknownOp);
+            }
+            _ => return Err(Error::UnknownOp),
+        }
+        Ok(())
+    }
+
+    /// Writes `left + right + carry` to `dest`, at the width of `dest`.
+    fn add_with_carry(
+        &mut self,
+        dest: Reg,
+        left: u64,
+        right: u64,
+        carry: bool,
+        set_flags: bool,
+    ) -> Result<()> {
+        let (result, flags) = add_with_carry(left, right, carry, is_w_reg(dest));
+        if set_flags {
+            self.registers.nzcv.set(flags);
+        }
+        self.set_reg(dest, result)
+    }
+
+    fn add_sub(&mut self, operands: &[Operand], subtract: bool, set_flags: bool) -> Result<()> {
+        let dest = get_reg(&operands[0])?;
+        let left = self.reg(get_reg(&operands[1])?)?;
+        let right = self.operand(&operands[2], is_w_reg(dest))?;
+        if subtract {
+            self.add_with_carry(dest, left, !right, true, set_flags)?;
+        } else {
+            self.add_with_carry(dest, left, right, false, set_flags)?;
+        }
+        Ok(())
+    }
+
+    /// CMP/CMN, `rn, op2`.
+    fn compare(&mut self, operands: &[Operand], subtract: bool) -> Result<()> {
+        let left_reg = get_reg(&operands[0])?;
+        let zero_reg = if is_w_reg(left_reg) {
+            Reg::WZR
+        } else {
+            Reg::XZR
+        };
+        let left = self.reg(left_reg)?;
+        let right = self.operand(&operands[1], is_w_reg(left_reg))?;
+        if subtract {
+            self.add_with_carry(zero_reg, left, !right, true, true)?;
+        } else {
+            self.add_with_carry(zero_reg, left, right, false, true)?;
+        }
+        Ok(())
+    }
+
+    fn logical(
+        &mut self,
+        operands: &[Operand],
+        op: fn(u64, u64) -> u64,
+        set_flags: bool,
+    ) -> Result<()> {
+        let dest = get_reg(&operands[0])?;
+        let is_32bit = is_w_reg(dest);
+        let left = self.reg(get_reg(&operands[1])?)?;
+        let right = self.operand(&operands[2], is_32bit)?;
+        let result = mask(op(left, right), is_32bit);
+        if set_flags {
+            self.registers.nzcv.set(logical_flags(result, is_32bit));
+        }
+        self.set_reg(dest, result)?;
+        Ok(())
+    }
+
+    /// MOV and FMOV between general-purpose registers, SIMD/FP registers and
+    /// their lanes, or from an immediate. Writing a SIMD/FP view or
+    /// arrangement clears the rest of the vector, writing a lane keeps it.
+    fn mov(&mut self, operands: &[Operand]) -> Result<()> {
+        let value = match &operands[1] {
+            Operand::Reg { reg, arrspec } if is_vreg(*reg) => {
+                let vector = *self.registers.borrow_mut_vreg(*reg)?;
+                from_bytes(&vector.to_le_bytes()[vector_bytes(*reg, *arrspec)])
+            }
+            Operand::Reg { reg, .. } => self.reg(*reg)? as u128,
+            Operand::Imm32 { imm, .. } | Operand::Imm64 { imm, .. } => imm_value(imm) as u128,
+            operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+        };
+        match &operands[0] {
+            Operand::Reg { reg, arrspec } if is_vreg(*reg) => {
+                let is_lane = arrspec.and_then(|arrspec| arrspec.lane()).is_some();
+                let mut vector = if is_lane {
+                    self.registers.borrow_mut_vreg(*reg)?.to_le_bytes()
+                } else {
+                    [0; 16]
+                };
+                let bytes = vector_bytes(*reg, *arrspec);
+                let size = bytes.len();
+                vector[bytes].copy_from_slice(&value.to_le_bytes()[..size]);
+                *self.registers.borrow_mut_vreg(*reg)? = u128::from_le_bytes(vector);
+            }
+            Operand::Reg { reg, .. } => self.set_reg(*reg, value as u64)?,
+            operand => return Err(Error::UnsupportedOperand(operand.to_string())),
+        }
+        Ok(())
+    }
+
+    /// The UBFM/SBFM/BFM aliases, `rd, rn, lsb, width`.
+    fn bitfield(&mut self, op: Op, operands: &[Operand]) -> Result<()> {
+        let dest = get_reg(&operands[0])?;
+        let is_32bit = is_w_reg(dest);
+        let src = self.reg(get_reg(&operands[1])?)?;
+        let lsb = get_imm(&operands[2])? as u32;
+        let bits = get_imm(&operands[3])? as u32;
+        let field = low_bits(bits);
+
+        let value = match op {
+            Op::UBFX => (src >> lsb) & field,
+            Op::SBFX => sign_extend((src >> lsb) & field, bits),
+            Op::UBFIZ => (src & field) << lsb,
+            Op::SBFIZ => sign_extend(src & field, bits) << lsb,
+            Op::BFI => self.reg(dest)? & !(field << lsb) | (src & field) << lsb,
+            _ => self.reg(dest)? & !field | (src >> lsb) & field,
+        };
+        self.set_reg(dest, mask(value, is_32bit))?;
+        Ok(())
+    }
+
+    /// CSEL and friends, `op` is applied to the second source when `cond`
+    /// fails. The CSET/CSETM/CINC/CINV/CNEG aliases pass `rd, [rn,] cond`,
+    /// which selects the modified `rn` when `cond` holds.
+    fn select(&mut self, operands: &[Operand], op: fn(u64) -> u64) -> Result<()> {
+        let dest = get_reg(&operands[0])?;
+        let (true_value, false_value, cond) = match operands {
+            [_, cond] => (0, 0, invert_condition(get_cond(cond)?)),
+            [_, source, cond] => {
+                let value = self.reg(get_reg(source)?)?;
+                (value, value, invert_condition(get_cond(cond)?))
+            }
+            [_, true_op, false_op, cond] => {
+                let true_value = self.reg(get_reg(true_op)?)?;
+                let false_value = self.reg(get_reg(false_op)?)?;
+                (true_value, false_value, get_cond(cond)?)
+            }
+            _ => unreachable!("Conditional selects take 2 to 4 operands"),
+        };
+        let value = if condition_holds(cond, self.nzcv()?) {
+            true_value
+        } else {
+            op(false_value)
+        };
+        self.set_reg(dest, value)?;
+        Ok(())
+    }
+
+    /// Guest address of a memory operand, plus the new base register value for
+    /// pre- and post-indexed forms.
+    fn address(&mut self, operand: &Operand) -> Result<(u64, Option<(Reg, u64)>)> {
+        let address = match operand {
+            Operand::MemReg(reg) => (self.reg(*reg)?, None),
+            Operand::MemOffset { reg, offset, .. } => {
+                (self.reg(*reg)?.wrapping_add(imm_value(offset)), None)
+            }
+            Operand::MemPreIdx { reg, imm } => {
+                let addr = self.reg(*reg)?.wrapping_add(imm_value(imm));
+                (addr, Some((*reg, addr)))
+            }
+            Operand::MemPostIdxImm { reg, imm } => {
+                let addr = self.reg(*reg)?;
+                (addr, Some((*reg, addr.wrapping_add(imm_value(imm)))))
+            }
+            Operand::MemExt { regs, shift, .. } => {
+                let base = self.reg(regs[0])?;
+                let index = self.reg(regs[1])?;
+                let index = match shift {
+                    None => index,
+                    Some(Shift::SXTW(amount)) => sign_extend(index, 32) << amount,
+                    Some(Shift::LSL(amount))
+                    | Some(Shift::UXTW(amount))
+                    | Some(Shift::UXTX(amount))
+                    | Some(Shift::SXTX(amount)) => index << amount,
+                    Some(shift) => return Err(Error::UnsupportedOperand(shift.to_string())),
+                };
+                (base.wrapping_add(index), None)
+            }
+            Operand::Label(imm) => (imm_value(imm), None),
+            _ => return Err(Error::UnsupportedOperand(operand.to_string())),
+        };
+        Ok(address)
+    }
+
+    fn writeback(&mut self, writeback: Option<(Reg, u64)>) -> Result<()> {
+        match writeback {
+            Some((reg, value)) => self.set_reg(reg, value),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes `size` loaded bytes to `dest`, sign or zero extending them to the
+    /// register width.
+    fn set_loaded(&mut self, dest: Reg, value: u128, size: usize, signed: bool) -> Result<()> {
+        if is_vreg(dest) {
+            self.set_vreg(dest, value)
+        } else if signed {
+            self.set_reg(dest, sign_extend(value as u64, size as u32 * 8))
+        } else {
+            self.set_reg(dest, value as u64)
+        }
+    }
+
+    /// The low `size` bytes of the guest register `src`.
+    fn stored(&mut self, src: Reg, size: usize) -> Result<Vec<u8>> {
+        let value = if is_vreg(src) {
+            self.vreg(src)?
+        } else {
+            self.reg(src)? as u128
+        };
+        Ok(value.to_le_bytes()[..size].to_vec())
+    }
+
+    fn write(&mut self, addr: u64, bytes: &[u8]) -> Result<()> {
+        let mut previous = vec![0; bytes.len()];
+        self.memory.read_bytes(addr, &mut previous)?;
+        self.memory.write_bytes(addr, bytes)?;
+        self.write = Some(MemoryWrite { addr, previous });
+        Ok(())
+    }
+
+    /// Single register load, `size` of `None` means the width of the destination.
+    fn load(&mut self, operands: &[Operand], size: Option<usize>, signed: bool) -> Result<()> {
+        let dest = get_reg(&operands[0])?;
+        let size = size.unwrap_or_else(|| dest.size());
+        let (addr, writeback) = self.address(&operands[1])?;
+
+        let mut buf = [0u8; 16];
+        self.memory.read_bytes(addr, &mut buf[..size])?;
+        self.set_loaded(dest, from_bytes(&buf[..size]), size, signed)?;
+        self.writeback(writeback)?;
+        Ok(())
+    }
+
+    fn store(&mut self, operands: &[Operand], size: Option<usize>) -> Result<()> {
+        let src = get_reg(&operands[0])?;
+        let size = size.unwrap_or_else(|| src.size());
+        let (addr, writeback) = self.address(&operands[1])?;
+
+        let bytes = self.stored(src, size)?;
+        self.write(addr, &bytes)?;
+        self.writeback(writeback)?;
+        Ok(())
+    }
+
+    /// Both registers are accessed at once, so a fault leaves them unchanged.
+    fn load_pair(&mut self, operands: &[Operand], signed: bool) -> Result<()> {
+        let first = get_reg(&operands[0])?;
+        let second = get_reg(&operands[1])?;
+        let size = if signed { 4 } else { first.size() };
+        let (addr, writeback) = self.address(&operands[2])?;
+
+        let mut buf = [0u8; 32];
+        self.memory.read_bytes(addr, &mut buf[..size * 2])?;
+        self.set_loaded(first, from_bytes(&buf[..size]), size, signed)?;
+        self.set_loaded(second, from_bytes(&buf[size..size * 2]), size, signed)?;
+        self.writeback(writeback)?;
+        Ok(())
+    }
+
+    fn store_pair(&mut self, operands: &[Operand]) -> Result<()> {
+        let first = get_reg(&operands[0])?;
+        let second = get_reg(&operands[1])?;
+        let size = first.size();
+        let (addr, writeback) = self.address(&operands[2])?;
+
+        let mut bytes = self.stored(first, size)?;
+        bytes.extend(self.stored(second, size)?);
+        self.write(addr, &bytes)?;
+        self.writeback(writeback)?;
+        Ok(())
+    }
+}
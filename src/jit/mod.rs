@@ -1,9 +1,18 @@
 pub mod assembler;
+pub mod code_cache;
 pub mod context;
 pub mod emitter_arithmetic;
 pub mod emitter_bit;
 pub mod emitter_branch;
 pub mod emitter_cmp;
 pub mod emitter_mem;
+pub mod emitter_select;
+pub mod emitter_sys;
+pub mod interpreter;
 pub mod parser;
+pub mod register_allocator;
 pub mod utils;
+pub mod verifier;
+
+#[cfg(test)]
+mod tests;
@@ -1,36 +1,287 @@
+use crate::error::{Error, Result};
 use crate::jit::assembler::instructions_assembler::InstAssembler;
 use crate::jit::context::Context;
-use crate::jit::emitter_arithmetic::{emit_add, emit_adr, emit_sub, emit_subs};
-use crate::jit::emitter_bit::emit_and;
-use crate::jit::emitter_branch::{emit_beq, emit_bne};
-use crate::jit::emitter_cmp::{emit_ccmn, emit_cmn, emit_cmp};
-use crate::jit::emitter_mem::{emit_ldp, emit_mov, emit_str};
-use bad64::Op;
-
-pub fn parse_inst(context: &mut Context, assembler: &mut InstAssembler, inst: &u32) -> bool {
-    let inst_decoded = bad64::decode(*inst, 0).unwrap();
-    println!("{}", inst_decoded);
-
-    let operands = inst_decoded.operands();
-    let parse = match inst_decoded.op() {
-        Op::ADD => emit_add,
-        Op::ADR => emit_adr,
-        Op::SUB => emit_sub,
-        Op::SUBS => emit_subs,
-
-        Op::AND => emit_and,
-
-        Op::B_EQ => emit_beq,
-        Op::B_NE => emit_bne,
-
-        Op::CMP => emit_cmp,
-        Op::CMN => emit_cmn,
-        Op::CCMN => emit_ccmn,
-
-        Op::LDP => emit_ldp,
-        Op::MOV => emit_mov,
-        Op::STR => emit_str,
-        _ => panic!("Unknown op {}", inst_decoded),
-    };
-    parse(context, assembler, operands)
+use crate::jit::emitter_arithmetic::{
+    emit_adc, emit_adcs, emit_add, emit_adds, emit_adr, emit_neg, emit_negs, emit_sbc, emit_sbcs,
+    emit_sub, emit_subs,
+};
+use crate::jit::emitter_bit::{
+    emit_and, emit_ands, emit_bic, emit_bics, emit_eon, emit_eor, emit_mvn, emit_orn, emit_orr,
+    emit_tst,
+};
+use crate::jit::emitter_branch::{
+    emit_b, emit_bal, emit_bcc, emit_bcs, emit_beq, emit_bge, emit_bgt, emit_bhi, emit_bl,
+    emit_ble, emit_blr, emit_bls, emit_blt, emit_bmi, emit_bne, emit_bnv, emit_bpl, emit_br,
+    emit_bvc, emit_bvs, emit_cbnz, emit_cbz, emit_ret, emit_tbnz, emit_tbz,
+};
+use crate::jit::emitter_cmp::{emit_ccmn, emit_ccmp, emit_cmn, emit_cmp};
+use crate::jit::emitter_mem::{
+    emit_ldp, emit_ldpsw, emit_ldr, emit_ldrb, emit_ldrh, emit_ldrsb, emit_ldrsh, emit_ldrsw,
+    emit_mov, emit_stp, emit_str, emit_strb, emit_strh,
+};
+use crate::jit::emitter_select::{
+    emit_cinc, emit_cinv, emit_cneg, emit_csel, emit_cset, emit_csetm, emit_csinc, emit_csinv,
+    emit_csneg,
+};
+use crate::jit::emitter_sys::{emit_mrs, emit_msr, known_sys_reg};
+use bad64::{Condition, Instruction, Op, Operand};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Translates one guest instruction, returns false if it ended the block.
+pub type Emitter = fn(&mut Context, &mut InstAssembler, &[Operand]) -> Result<bool>;
+
+/// A set of guest NZCV flags.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    pub const N: Flags = Flags(0b1000);
+    pub const Z: Flags = Flags(0b0100);
+    pub const C: Flags = Flags(0b0010);
+    pub const V: Flags = Flags(0b0001);
+    pub const NZCV: Flags = Flags(0b1111);
+
+    pub const fn union(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/// Flags `cond` tests.
+pub const fn condition_flags(cond: Condition) -> Flags {
+    match cond {
+        Condition::EQ | Condition::NE => Flags::Z,
+        Condition::CS | Condition::CC => Flags::C,
+        Condition::MI | Condition::PL => Flags::N,
+        Condition::VS | Condition::VC => Flags::V,
+        Condition::HI | Condition::LS => Flags::C.union(Flags::Z),
+        Condition::GE | Condition::LT => Flags::N.union(Flags::V),
+        Condition::GT | Condition::LE => Flags::N.union(Flags::Z).union(Flags::V),
+        Condition::AL | Condition::NV => Flags::NONE,
+    }
+}
+
+/// How an op is translated, along with what the block builder needs to know
+/// about it without looking at the emitter. Ops that take their condition as
+/// an operand, or may read NZCV as a system register, count as reading every
+/// flag. Written flags are only listed if the op always writes them.
+pub struct OpInfo {
+    pub emit: Emitter,
+    /// Whether the emitter handles these operands, forms it doesn't are left
+    /// to the interpreter
+    pub accepts: fn(&[Operand]) -> bool,
+    /// Control never reaches the next instruction through the block
+    pub ends_block: bool,
+    pub flags_read: Flags,
+    pub flags_written: Flags,
+    /// Accesses guest memory, which may fault or hit a code page
+    pub can_fault: bool,
+}
+
+impl OpInfo {
+    const fn new(emit: Emitter) -> Self {
+        OpInfo {
+            emit,
+            accepts: no_vector_operands,
+            ends_block: false,
+            flags_read: Flags::NONE,
+            flags_written: Flags::NONE,
+            can_fault: false,
+        }
+    }
+
+    const fn ends_block(self) -> Self {
+        OpInfo {
+            ends_block: true,
+            ..self
+        }
+    }
+
+    const fn reads(self, flags: Flags) -> Self {
+        OpInfo {
+            flags_read: flags,
+            ..self
+        }
+    }
+
+    const fn writes(self, flags: Flags) -> Self {
+        OpInfo {
+            flags_written: flags,
+            ..self
+        }
+    }
+
+    const fn accepts(self, accepts: fn(&[Operand]) -> bool) -> Self {
+        OpInfo { accepts, ..self }
+    }
+
+    const fn can_fault(self) -> Self {
+        OpInfo {
+            can_fault: true,
+            ..self
+        }
+    }
+}
+
+/// Rejects the SIMD forms sharing an op with a scalar one, whose operands
+/// carry an arrangement or a lane, like `add v0.4s, v1.4s, v2.4s`.
+fn no_vector_operands(operands: &[Operand]) -> bool {
+    operands.iter().all(|operand| match operand {
+        Operand::Reg { arrspec, .. } => arrspec.is_none(),
+        Operand::MultiReg { .. } | Operand::IndexedElement { .. } => false,
+        _ => true,
+    })
+}
+
+const fn cond_branch(emit: Emitter, cond: Condition) -> OpInfo {
+    OpInfo::new(emit).ends_block().reads(condition_flags(cond))
+}
+
+/// Every op the JIT translates. New emitters only need an entry here.
+const OPS: &[(&[Op], OpInfo)] = &[
+    (&[Op::ADD], OpInfo::new(emit_add)),
+    (&[Op::ADDS], OpInfo::new(emit_adds).writes(Flags::NZCV)),
+    (&[Op::ADR, Op::ADRP], OpInfo::new(emit_adr)),
+    (&[Op::SUB], OpInfo::new(emit_sub)),
+    (&[Op::SUBS], OpInfo::new(emit_subs).writes(Flags::NZCV)),
+    (&[Op::NEG], OpInfo::new(emit_neg)),
+    (&[Op::NEGS], OpInfo::new(emit_negs).writes(Flags::NZCV)),
+    (&[Op::ADC], OpInfo::new(emit_adc).reads(Flags::C)),
+    (
+        &[Op::ADCS],
+        OpInfo::new(emit_adcs).reads(Flags::C).writes(Flags::NZCV),
+    ),
+    (&[Op::SBC], OpInfo::new(emit_sbc).reads(Flags::C)),
+    (
+        &[Op::SBCS],
+        OpInfo::new(emit_sbcs).reads(Flags::C).writes(Flags::NZCV),
+    ),
+    //
+    (&[Op::AND], OpInfo::new(emit_and)),
+    (&[Op::ANDS], OpInfo::new(emit_ands).writes(Flags::NZCV)),
+    (&[Op::TST], OpInfo::new(emit_tst).writes(Flags::NZCV)),
+    (&[Op::BIC], OpInfo::new(emit_bic)),
+    (&[Op::BICS], OpInfo::new(emit_bics).writes(Flags::NZCV)),
+    (&[Op::ORR], OpInfo::new(emit_orr)),
+    (&[Op::ORN], OpInfo::new(emit_orn)),
+    (&[Op::MVN], OpInfo::new(emit_mvn)),
+    (&[Op::EOR], OpInfo::new(emit_eor)),
+    (&[Op::EON], OpInfo::new(emit_eon)),
+    //
+    (&[Op::B], OpInfo::new(emit_b).ends_block()),
+    (&[Op::BL], OpInfo::new(emit_bl).ends_block()),
+    (&[Op::BR], OpInfo::new(emit_br).ends_block()),
+    (&[Op::BLR], OpInfo::new(emit_blr).ends_block()),
+    (&[Op::RET], OpInfo::new(emit_ret).ends_block()),
+    (&[Op::CBZ], OpInfo::new(emit_cbz).ends_block()),
+    (&[Op::CBNZ], OpInfo::new(emit_cbnz).ends_block()),
+    (&[Op::TBZ], OpInfo::new(emit_tbz).ends_block()),
+    (&[Op::TBNZ], OpInfo::new(emit_tbnz).ends_block()),
+    (&[Op::B_EQ], cond_branch(emit_beq, Condition::EQ)),
+    (&[Op::B_NE], cond_branch(emit_bne, Condition::NE)),
+    (&[Op::B_CS], cond_branch(emit_bcs, Condition::CS)),
+    (&[Op::B_CC], cond_branch(emit_bcc, Condition::CC)),
+    (&[Op::B_MI], cond_branch(emit_bmi, Condition::MI)),
+    (&[Op::B_PL], cond_branch(emit_bpl, Condition::PL)),
+    (&[Op::B_VS], cond_branch(emit_bvs, Condition::VS)),
+    (&[Op::B_VC], cond_branch(emit_bvc, Condition::VC)),
+    (&[Op::B_HI], cond_branch(emit_bhi, Condition::HI)),
+    (&[Op::B_LS], cond_branch(emit_bls, Condition::LS)),
+    (&[Op::B_GE], cond_branch(emit_bge, Condition::GE)),
+    (&[Op::B_LT], cond_branch(emit_blt, Condition::LT)),
+    (&[Op::B_GT], cond_branch(emit_bgt, Condition::GT)),
+    (&[Op::B_LE], cond_branch(emit_ble, Condition::LE)),
+    (&[Op::B_AL], cond_branch(emit_bal, Condition::AL)),
+    (&[Op::B_NV], cond_branch(emit_bnv, Condition::NV)),
+    //
+    (&[Op::CMP], OpInfo::new(emit_cmp).writes(Flags::NZCV)),
+    (&[Op::CMN], OpInfo::new(emit_cmn).writes(Flags::NZCV)),
+    (
+        &[Op::CCMP],
+        OpInfo::new(emit_ccmp)
+            .reads(Flags::NZCV)
+            .writes(Flags::NZCV),
+    ),
+    (
+        &[Op::CCMN],
+        OpInfo::new(emit_ccmn)
+            .reads(Flags::NZCV)
+            .writes(Flags::NZCV),
+    ),
+    //
+    (&[Op::CSEL], OpInfo::new(emit_csel).reads(Flags::NZCV)),
+    (&[Op::CSINC], OpInfo::new(emit_csinc).reads(Flags::NZCV)),
+    (&[Op::CSINV], OpInfo::new(emit_csinv).reads(Flags::NZCV)),
+    (&[Op::CSNEG], OpInfo::new(emit_csneg).reads(Flags::NZCV)),
+    (&[Op::CSET], OpInfo::new(emit_cset).reads(Flags::NZCV)),
+    (&[Op::CSETM], OpInfo::new(emit_csetm).reads(Flags::NZCV)),
+    (&[Op::CINC], OpInfo::new(emit_cinc).reads(Flags::NZCV)),
+    (&[Op::CINV], OpInfo::new(emit_cinv).reads(Flags::NZCV)),
+    (&[Op::CNEG], OpInfo::new(emit_cneg).reads(Flags::NZCV)),
+    //
+    (&[Op::LDR, Op::LDUR], OpInfo::new(emit_ldr).can_fault()),
+    (&[Op::LDRB, Op::LDURB], OpInfo::new(emit_ldrb).can_fault()),
+    (&[Op::LDRH, Op::LDURH], OpInfo::new(emit_ldrh).can_fault()),
+    (
+        &[Op::LDRSB, Op::LDURSB],
+        OpInfo::new(emit_ldrsb).can_fault(),
+    ),
+    (
+        &[Op::LDRSH, Op::LDURSH],
+        OpInfo::new(emit_ldrsh).can_fault(),
+    ),
+    (
+        &[Op::LDRSW, Op::LDURSW],
+        OpInfo::new(emit_ldrsw).can_fault(),
+    ),
+    (&[Op::LDP, Op::LDNP], OpInfo::new(emit_ldp).can_fault()),
+    (&[Op::LDPSW], OpInfo::new(emit_ldpsw).can_fault()),
+    (&[Op::MOV], OpInfo::new(emit_mov)),
+    (&[Op::STR, Op::STUR], OpInfo::new(emit_str).can_fault()),
+    (&[Op::STRB, Op::STURB], OpInfo::new(emit_strb).can_fault()),
+    (&[Op::STRH, Op::STURH], OpInfo::new(emit_strh).can_fault()),
+    (&[Op::STP, Op::STNP], OpInfo::new(emit_stp).can_fault()),
+    //
+    (
+        &[Op::MRS],
+        OpInfo::new(emit_mrs)
+            .accepts(known_sys_reg)
+            .reads(Flags::NZCV),
+    ),
+    (&[Op::MSR], OpInfo::new(emit_msr).accepts(known_sys_reg)),
+];
+
+/// Returns how `op` is translated, `None` if the JIT has no emitter for it.
+pub fn op_info(op: Op) -> Option<&'static OpInfo> {
+    static TABLE: OnceLock<HashMap<Op, &'static OpInfo>> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = HashMap::new();
+        for (ops, info) in OPS {
+            for op in *ops {
+                let previous = table.insert(*op, info);
+                assert!(previous.is_none(), "{:?} registered twice", op);
+            }
+        }
+        table
+    });
+    table.get(&op).copied()
+}
+
+/// Returns how `inst` is translated, `None` if the JIT has no emitter for its
+/// op or its operands.
+pub fn inst_info(inst: &Instruction) -> Option<&'static OpInfo> {
+    op_info(inst.op()).filter(|info| (info.accepts)(inst.operands()))
+}
+
+/// Decodes the instruction word `inst` at `pc` and looks up how it is
+/// translated, `None` if it is left to the interpreter.
+pub fn parse_inst(pc: u64, inst: u32) -> Result<(Instruction, Option<&'static OpInfo>)> {
+    let inst_decoded = bad64::decode(inst, pc).map_err(|_| Error::UndefinedInstruction)?;
+    let info = inst_info(&inst_decoded);
+    Ok((inst_decoded, info))
 }
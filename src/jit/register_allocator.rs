@@ -0,0 +1,257 @@
+use crate::error::Result;
+use crate::jit::assembler::instructions_assembler::{Inst, InstAssembler};
+use crate::jit::assembler::registers_handler::{map_reg_32, CALLEE_SAVED_REGISTERS};
+use crate::jit::context::{gpr_slot, is_w_reg, Registers};
+use bad64::Reg;
+use iced_x86::{Code, Register};
+use std::mem;
+
+/// A guest register access made while emitting a block, registers given by
+/// their `gpr_slot`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Access {
+    Read(usize),
+    Write(usize),
+    /// Leaving the block, every guest register is needed in memory
+    Exit,
+}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    guest: usize,
+    /// Holds a value that is not written back to the guest registers yet
+    dirty: bool,
+}
+
+/// Keeps guest general-purpose registers in callee-saved host registers for
+/// the duration of a block. Emitters only take caller-saved scratch registers
+/// from `RegistersHandler`, so the two never clash.
+///
+/// Blocks are emitted twice. The first pass records the guest register
+/// accesses, the second uses them as liveness to pick spill victims and to
+/// skip loads and write-backs of values nothing reads anymore.
+pub struct RegisterAllocator {
+    slots: [Option<Slot>; CALLEE_SAVED_REGISTERS.len()],
+    /// Accesses of the pass being emitted
+    accesses: Vec<Access>,
+    /// Accesses recorded by the first pass, empty during it
+    recorded: Vec<Access>,
+    /// See `freeze`
+    frozen: bool,
+}
+
+impl RegisterAllocator {
+    pub fn new() -> Self {
+        RegisterAllocator {
+            slots: [None; CALLEE_SAVED_REGISTERS.len()],
+            accesses: Vec::new(),
+            recorded: Vec::new(),
+            frozen: false,
+        }
+    }
+
+    /// Starts the first pass over a block, which only records accesses.
+    pub fn begin_recording(&mut self) {
+        self.recorded.clear();
+        self.accesses.clear();
+        self.slots = [None; CALLEE_SAVED_REGISTERS.len()];
+        self.frozen = false;
+    }
+
+    /// Starts the second pass over the block just recorded.
+    pub fn begin_allocating(&mut self) {
+        self.recorded = mem::take(&mut self.accesses);
+        self.slots = [None; CALLEE_SAVED_REGISTERS.len()];
+        self.frozen = false;
+    }
+
+    /// Code emitted until `thaw` may be skipped at runtime, so it must leave
+    /// the allocation as it found it. Registers that aren't held already are
+    /// accessed in memory meanwhile.
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    pub fn thaw(&mut self) {
+        self.frozen = false;
+    }
+
+    /// Copies the guest register `reg` into `dest`. W registers are zero
+    /// extended.
+    pub fn emit_read(
+        &mut self,
+        asm: &mut InstAssembler,
+        registers: &mut Registers,
+        reg: Reg,
+        dest: Register,
+    ) -> Result<()> {
+        let guest = gpr_slot(reg)?;
+        self.record(Access::Read(guest));
+
+        let host = match self.find(guest) {
+            Some(index) => CALLEE_SAVED_REGISTERS[index],
+            None if self.frozen || self.next_read(guest).is_none() => {
+                // Not worth a host register, nothing reads it again
+                if is_w_reg(reg) {
+                    asm.emit_var_to_reg32(registers.borrow_mut_gpr(guest), dest);
+                } else {
+                    asm.emit_var_to_reg(registers.borrow_mut_gpr(guest), dest);
+                }
+                return Ok(());
+            }
+            None => {
+                let index = self.allocate(asm, registers);
+                let host = CALLEE_SAVED_REGISTERS[index];
+                asm.emit_var_to_reg(registers.borrow_mut_gpr(guest), host);
+                self.slots[index] = Some(Slot {
+                    guest,
+                    dirty: false,
+                });
+                host
+            }
+        };
+
+        if is_w_reg(reg) {
+            asm.uw_add(Inst::with2(
+                Code::Mov_r32_rm32,
+                map_reg_32(&dest),
+                map_reg_32(&host),
+            ));
+        } else {
+            asm.uw_add(Inst::with2(Code::Mov_r64_rm64, dest, host));
+        }
+        Ok(())
+    }
+
+    /// Copies `src` into the guest register `reg`. W registers clear the upper
+    /// half.
+    pub fn emit_write(
+        &mut self,
+        asm: &mut InstAssembler,
+        registers: &mut Registers,
+        src: Register,
+        reg: Reg,
+    ) -> Result<()> {
+        let guest = gpr_slot(reg)?;
+        // A write that may be skipped doesn't end the old value's life
+        self.record(if self.frozen {
+            Access::Read(guest)
+        } else {
+            Access::Write(guest)
+        });
+
+        let index = match self.find(guest) {
+            Some(index) => index,
+            None if self.frozen => {
+                if is_w_reg(reg) {
+                    asm.emit_set_var32(src, registers.borrow_mut_gpr(guest));
+                } else {
+                    asm.emit_set_var(src, registers.borrow_mut_gpr(guest));
+                }
+                return Ok(());
+            }
+            None => self.allocate(asm, registers),
+        };
+
+        let host = CALLEE_SAVED_REGISTERS[index];
+        if is_w_reg(reg) {
+            asm.uw_add(Inst::with2(
+                Code::Mov_r32_rm32,
+                map_reg_32(&host),
+                map_reg_32(&src),
+            ));
+        } else {
+            asm.uw_add(Inst::with2(Code::Mov_r64_rm64, host, src));
+        }
+        self.slots[index] = Some(Slot { guest, dirty: true });
+        Ok(())
+    }
+
+    /// Writes the modified guest registers back before leaving the block. The
+    /// allocation is kept, code behind a side exit still finds the registers
+    /// where they were.
+    pub fn emit_flush(&mut self, asm: &mut InstAssembler, registers: &mut Registers) {
+        self.record(Access::Exit);
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(Slot { guest, dirty: true }) = slot {
+                asm.emit_set_var(
+                    CALLEE_SAVED_REGISTERS[index],
+                    registers.borrow_mut_gpr(*guest),
+                );
+            }
+        }
+    }
+
+    fn record(&mut self, access: Access) {
+        if let Some(recorded) = self.recorded.get(self.accesses.len()) {
+            assert_eq!(
+                *recorded, access,
+                "Passes accessed guest registers differently"
+            );
+        }
+        self.accesses.push(access);
+    }
+
+    fn find(&self, guest: usize) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some(slot) if slot.guest == guest))
+    }
+
+    /// Position of the next read of `guest` after the current access, `None` if
+    /// it's overwritten first or never read again. While recording every
+    /// register counts as read right away.
+    fn next_read(&self, guest: usize) -> Option<usize> {
+        if self.recorded.is_empty() {
+            return Some(self.accesses.len());
+        }
+        for (pos, access) in self.recorded.iter().enumerate().skip(self.accesses.len()) {
+            match *access {
+                Access::Read(read) if read == guest => return Some(pos),
+                Access::Write(written) if written == guest => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Whether the current value of `guest` is still needed, by a read or by
+    /// leaving the block.
+    fn is_live(&self, guest: usize) -> bool {
+        if self.recorded.is_empty() {
+            return true;
+        }
+        for access in &self.recorded[self.accesses.len()..] {
+            match *access {
+                Access::Read(read) if read == guest => return true,
+                Access::Write(written) if written == guest => return false,
+                Access::Exit => return true,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Returns a free slot, spilling the held register whose next read is the
+    /// furthest away if there is none.
+    fn allocate(&mut self, asm: &mut InstAssembler, registers: &mut Registers) -> usize {
+        if let Some(index) = self.slots.iter().position(Option::is_none) {
+            return index;
+        }
+
+        let index = (0..self.slots.len())
+            .max_by_key(|&index| {
+                let guest = self.slots[index].unwrap().guest;
+                self.next_read(guest).unwrap_or(usize::MAX)
+            })
+            .unwrap();
+        let slot = self.slots[index].take().unwrap();
+        if slot.dirty && self.is_live(slot.guest) {
+            asm.emit_set_var(
+                CALLEE_SAVED_REGISTERS[index],
+                registers.borrow_mut_gpr(slot.guest),
+            );
+        }
+        index
+    }
+}
@@ -0,0 +1,87 @@
+use crate::jit::assembler::instructions_assembler::{CallSite, HostCode, LinkSite};
+use crate::jit::code_cache::CodeCache;
+use crate::jit::tests::Harness;
+
+const BLOCK_SIZE: usize = 16;
+
+/// A block whose code is the rel32 of a link jump at offset 0, followed by the
+/// rel32 of each host call.
+fn block(links: &[u64], calls: &[u64]) -> HostCode {
+    HostCode {
+        code: vec![0; BLOCK_SIZE],
+        links: links
+            .iter()
+            .map(|&target| LinkSite { target, offset: 0 })
+            .collect(),
+        calls: calls
+            .iter()
+            .enumerate()
+            .map(|(index, &fun)| CallSite {
+                fun,
+                offset: 4 + index * 4,
+            })
+            .collect(),
+    }
+}
+
+/// Host address the rel32 at `offset` of the block at `pc` jumps to.
+fn rel32_target(cache: &mut CodeCache, pc: u64, offset: u64) -> u64 {
+    let site = cache.lookup(pc).unwrap().unwrap().0 + offset;
+    let rel = unsafe { std::ptr::read_unaligned(site as *const i32) };
+    (site + 4).wrapping_add(rel as u64)
+}
+
+#[test]
+fn linked_blocks_count_as_used() {
+    let mut cache = CodeCache::new(3 * BLOCK_SIZE).unwrap();
+    cache.insert(0x1000..0x1004, block(&[], &[])).unwrap();
+    cache.insert(0x3000..0x3004, block(&[], &[])).unwrap();
+    // Linking to the first block makes the second the least recently used
+    cache.insert(0x2000..0x2004, block(&[0x1000], &[])).unwrap();
+    cache.insert(0x4000..0x4004, block(&[], &[])).unwrap();
+    assert!(cache.lookup(0x3000).unwrap().is_none());
+    assert!(cache.lookup(0x1000).unwrap().is_some());
+}
+
+#[test]
+fn links_follow_insertion_and_invalidation() {
+    let mut cache = CodeCache::new(4 * BLOCK_SIZE).unwrap();
+
+    let source = cache.insert(0x2000..0x2004, block(&[0x1000], &[])).unwrap();
+    assert_eq!(rel32_target(&mut cache, 0x2000, 0), source + 4);
+
+    let target = cache.insert(0x1000..0x1004, block(&[0x1000], &[])).unwrap();
+    assert_eq!(rel32_target(&mut cache, 0x2000, 0), target);
+    assert_eq!(rel32_target(&mut cache, 0x1000, 0), target);
+
+    cache.invalidate(0x1000).unwrap();
+    assert_eq!(rel32_target(&mut cache, 0x2000, 0), source + 4);
+}
+
+#[test]
+fn far_host_calls_go_through_veneer() {
+    let mut cache = CodeCache::new(4 * BLOCK_SIZE).unwrap();
+    let near = cache.insert(0x1000..0x1004, block(&[], &[])).unwrap();
+    let far = near.wrapping_add(1 << 40);
+
+    cache
+        .insert(0x2000..0x2004, block(&[], &[near, far]))
+        .unwrap();
+    assert_eq!(rel32_target(&mut cache, 0x2000, 4), near);
+    let veneer = rel32_target(&mut cache, 0x2000, 8);
+    let veneer = unsafe { std::slice::from_raw_parts(veneer as *const u8, 14) };
+    assert_eq!(veneer[..6], [0xff, 0x25, 0, 0, 0, 0]);
+    assert_eq!(veneer[6..], far.to_le_bytes());
+}
+
+#[test]
+fn interpreted_block_heads_are_not_cached() {
+    let mut test = Harness::new(&[
+        0x5e0c0420, // mov s0, v1.s[1]
+        0x91000400, // add x0, x0, #1
+    ]);
+    test.run();
+    let code = test.code_addr();
+    assert!(test.context.code_cache.lookup(code).unwrap().is_none());
+    assert!(test.context.code_cache.lookup(code + 4).unwrap().is_some());
+}
@@ -0,0 +1,92 @@
+use crate::jit::tests::{Harness, C, N, V, Z};
+
+#[test]
+fn add_shifted_register() {
+    let mut test = Harness::new(&[
+        0x8b021020, // add x0, x1, x2, lsl #4
+    ]);
+    test.set_x(1, 1);
+    test.set_x(2, 0x10);
+    test.run();
+    assert_eq!(test.x(0), 0x101);
+}
+
+#[test]
+fn add_extended_register_to_sp() {
+    let mut test = Harness::new(&[
+        0x8b21cbe0, // add x0, sp, w1, sxtw #2
+    ]);
+    test.set_x(1, 0xFFFFFFFF);
+    test.run();
+    let sp = test.sp();
+    assert_eq!(test.x(0), sp - 4);
+}
+
+#[test]
+fn sub_w_clears_upper_half() {
+    let mut test = Harness::new(&[
+        0x4b020020, // sub w0, w1, w2
+    ]);
+    test.set_x(0, u64::MAX);
+    test.set_x(1, 0xFFFFFFFF_00000005);
+    test.set_x(2, 7);
+    test.run();
+    assert_eq!(test.x(0), 0xFFFFFFFE);
+}
+
+#[test]
+fn adds_wraps_to_zero_with_carry() {
+    let mut test = Harness::new(&[
+        0xab020020, // adds x0, x1, x2
+    ]);
+    test.set_x(1, u64::MAX);
+    test.set_x(2, 1);
+    test.run();
+    assert_eq!(test.x(0), 0);
+    assert_eq!(test.nzcv(), Z | C);
+}
+
+#[test]
+fn negs_w_of_min_overflows() {
+    let mut test = Harness::new(&[
+        0x6b0103e0, // negs w0, w1
+    ]);
+    test.set_x(1, 0x80000000);
+    test.run();
+    assert_eq!(test.x(0), 0x80000000);
+    assert_eq!(test.nzcv(), N | V);
+}
+
+#[test]
+fn adr_is_pc_relative() {
+    let mut test = Harness::new(&[
+        0x10000040, // adr x0, #8
+    ]);
+    test.run();
+    let code_addr = test.code_addr();
+    assert_eq!(test.x(0), code_addr + 8);
+}
+
+#[test]
+fn add_and_subtract_with_carry_flags() {
+    for (inst, left, right, nzcv, result, expected_nzcv) in [
+        // adcs x0, x1, x2
+        (0xba020020, u64::MAX, 0, C, 0, Z | C),
+        (0xba020020, 0x7FFF_FFFF_FFFF_FFFF, 0, C, 1 << 63, N | V),
+        // adcs w0, w1, w2
+        (0x3a020020, 0xFFFF_FFFF, 1, 0, 0, Z | C),
+        // sbcs x0, x1, x2
+        (0xfa020020, 0, 0, 0, u64::MAX, N),
+        (0xfa020020, 5, 3, C, 2, C),
+        // sbcs w0, w1, w2
+        (0x7a020020, 0x8000_0000, 1, C, 0x7FFF_FFFF, C | V),
+    ] {
+        let mut test = Harness::new(&[inst]);
+        test.set_nzcv(nzcv);
+        test.set_x(1, left);
+        test.set_x(2, right);
+        test.run();
+        assert_eq!(test.x(0), result, "{:#010x}", inst);
+        assert_eq!(test.nzcv(), expected_nzcv, "{:#010x}", inst);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::jit::tests::{Harness, N, Z};
+
+#[test]
+fn and_bitmask_immediate() {
+    let mut test = Harness::new(&[
+        0x927c1c20, // and x0, x1, #0xff0
+    ]);
+    test.set_x(1, 0x1234);
+    test.run();
+    assert_eq!(test.x(0), 0x230);
+}
+
+#[test]
+fn orr_w_shifted_register() {
+    let mut test = Harness::new(&[
+        0x2a421020, // orr w0, w1, w2, lsr #4
+    ]);
+    test.set_x(1, 1);
+    test.set_x(2, 0xFFFFFFFF_00000100);
+    test.run();
+    assert_eq!(test.x(0), 0x11);
+}
+
+#[test]
+fn eon_rotated_register() {
+    let mut test = Harness::new(&[
+        0xcae22020, // eon x0, x1, x2, ror #8
+    ]);
+    test.set_x(2, 0xFF);
+    test.run();
+    assert_eq!(test.x(0), 0x00FFFFFF_FFFFFFFF);
+}
+
+#[test]
+fn bics_sets_negative() {
+    let mut test = Harness::new(&[
+        0xea220020, // bics x0, x1, x2
+    ]);
+    test.set_x(1, 0x80000000_000000FF);
+    test.set_x(2, 0xFF);
+    test.run();
+    assert_eq!(test.x(0), 0x80000000_00000000);
+    assert_eq!(test.nzcv(), N);
+}
+
+#[test]
+fn tst_w_ignores_upper_half() {
+    let code = [
+        0x7201003f, // tst w1, #0x80000000
+    ];
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 0x80000000);
+    test.run();
+    assert_eq!(test.nzcv(), N);
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 0x1_00000000);
+    test.run();
+    assert_eq!(test.nzcv(), Z);
+}
+
+#[test]
+fn mvn_w() {
+    let mut test = Harness::new(&[
+        0x2a2103e0, // mvn w0, w1
+    ]);
+    test.set_x(1, 0xFFFFFFFF_000000FF);
+    test.run();
+    assert_eq!(test.x(0), 0xFFFFFF00);
+}
@@ -0,0 +1,65 @@
+use crate::jit::tests::Harness;
+
+#[test]
+fn cbz() {
+    let code = [
+        0xb4000041, // cbz x1, #8
+        0xd2800020, // mov x0, #1
+    ];
+
+    let mut test = Harness::new(&code);
+    test.run();
+    assert_eq!(test.x(0), 0);
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 1);
+    test.run();
+    assert_eq!(test.x(0), 1);
+}
+
+#[test]
+fn tbnz() {
+    let code = [
+        0x37180041, // tbnz w1, #3, #8
+        0xd2800020, // mov x0, #1
+    ];
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 8);
+    test.run();
+    assert_eq!(test.x(0), 0);
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 7);
+    test.run();
+    assert_eq!(test.x(0), 1);
+}
+
+#[test]
+fn bl_and_ret() {
+    let mut test = Harness::new(&[
+        0x94000002, // bl #8
+        0x14000003, // b #12
+        0xd2800020, // mov x0, #1
+        0xd65f03c0, // ret
+    ]);
+    test.run();
+    let code_addr = test.code_addr();
+    assert_eq!(test.x(0), 1);
+    assert_eq!(test.x(30), code_addr + 4);
+    assert_eq!(test.pc(), code_addr + 16);
+}
+
+#[test]
+fn b_ne_loop() {
+    let mut test = Harness::new(&[
+        0xd2800000, // mov x0, #0
+        0x8b010000, // add x0, x0, x1
+        0xf1000421, // subs x1, x1, #1
+        0x54ffffc1, // b.ne #-8
+    ]);
+    test.set_x(1, 4);
+    test.run();
+    assert_eq!(test.x(0), 10);
+    assert_eq!(test.x(1), 0);
+}
@@ -0,0 +1,78 @@
+use crate::jit::tests::{Harness, C, N, V, Z};
+
+#[test]
+fn cmp_w_borrows() {
+    let mut test = Harness::new(&[
+        0x6b02003f, // cmp w1, w2
+    ]);
+    test.set_x(1, 0x1_00000000);
+    test.set_x(2, 1);
+    test.run();
+    assert_eq!(test.nzcv(), N);
+}
+
+#[test]
+fn cmp_min_minus_one_overflows() {
+    let mut test = Harness::new(&[
+        0xeb02003f, // cmp x1, x2
+    ]);
+    test.set_x(1, 0x80000000_00000000);
+    test.set_x(2, 1);
+    test.run();
+    assert_eq!(test.nzcv(), C | V);
+}
+
+#[test]
+fn cmn_immediate_carries() {
+    let mut test = Harness::new(&[
+        0xb100043f, // cmn x1, #1
+    ]);
+    test.set_x(1, u64::MAX);
+    test.run();
+    assert_eq!(test.nzcv(), Z | C);
+}
+
+#[test]
+fn ccmp() {
+    let code = [
+        0xfa420025, // ccmp x1, x2, #0x5, eq
+    ];
+
+    // Condition fails, the immediate is taken
+    let mut test = Harness::new(&code);
+    test.set_nzcv(0);
+    test.run();
+    assert_eq!(test.nzcv(), Z | V);
+
+    let mut test = Harness::new(&code);
+    test.set_nzcv(Z);
+    test.set_x(1, 2);
+    test.set_x(2, 3);
+    test.run();
+    assert_eq!(test.nzcv(), N);
+}
+
+#[test]
+fn flags_from_previous_block() {
+    for (inst, left, right, nzcv, hi) in [
+        (0xab020020, u64::MAX, 1, Z | C, 0),   // adds x0, x1, x2
+        (0x2b020020, 0x7fffffff, 1, N | V, 0), // adds w0, w1, w2
+        (0x6b020020, 3, 2, C, 1),              // subs w0, w1, w2
+        (0xea020020, 1 << 63, 1 << 63, N, 0),  // ands x0, x1, x2
+    ] {
+        // The branch ends the block, so the conditions below don't know
+        // which operation set the flags. Verifying would compute them in
+        // between.
+        let mut test = Harness::new(&[
+            inst, 0x14000001, // b #4
+            0x1a9f97e3, // cset w3, hi
+            0xd53b4204, // mrs x4, nzcv
+        ]);
+        test.context.verify = false;
+        test.set_x(1, left);
+        test.set_x(2, right);
+        test.run();
+        assert_eq!(test.x(3), hi, "{:#x}", inst);
+        assert_eq!(test.x(4), nzcv, "{:#x}", inst);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::error::Error;
+use crate::jit::assembler::instructions_assembler::InstAssembler;
+use crate::jit::emitter_mem::emit_mov;
+use crate::jit::tests::Harness;
+use crate::jit::utils;
+
+#[test]
+fn ldr_pre_index() {
+    let mut test = Harness::new(&[
+        0xf8408c20, // ldr x0, [x1, #8]!
+    ]);
+    let data = test.data_addr();
+    test.write_u64(data + 8, 0x11223344_55667788);
+    test.set_x(1, data);
+    test.run();
+    assert_eq!(test.x(0), 0x11223344_55667788);
+    assert_eq!(test.x(1), data + 8);
+}
+
+#[test]
+fn str_post_index() {
+    let mut test = Harness::new(&[
+        0xf8010422, // str x2, [x1], #16
+    ]);
+    let data = test.data_addr();
+    test.set_x(1, data);
+    test.set_x(2, 0xABCDEF);
+    test.run();
+    assert_eq!(test.read_u64(data), 0xABCDEF);
+    assert_eq!(test.x(1), data + 16);
+}
+
+#[test]
+fn stp_then_ldp_w() {
+    let mut test = Harness::new(&[
+        0xa9bf0c22, // stp x2, x3, [x1, #-16]!
+        0x29401023, // ldp w3, w4, [x1]
+    ]);
+    let data = test.data_addr();
+    test.set_x(1, data + 16);
+    test.set_x(2, 0x1_00000002);
+    test.set_x(3, 5);
+    test.run();
+    assert_eq!(test.read_u64(data), 0x1_00000002);
+    assert_eq!(test.read_u64(data + 8), 5);
+    assert_eq!(test.x(1), data);
+    assert_eq!(test.x(3), 2);
+    assert_eq!(test.x(4), 1);
+}
+
+#[test]
+fn ldrsb_sign_extends() {
+    let mut test = Harness::new(&[
+        0x39800020, // ldrsb x0, [x1]
+    ]);
+    let data = test.data_addr();
+    test.write_u64(data, 0x80);
+    test.set_x(1, data);
+    test.run();
+    assert_eq!(test.x(0), 0xFFFFFFFF_FFFFFF80);
+}
+
+#[test]
+fn ldrh_scaled_register_offset() {
+    let mut test = Harness::new(&[
+        0x78627820, // ldrh w0, [x1, x2, lsl #1]
+    ]);
+    let data = test.data_addr();
+    test.write_u64(data, 0x4444_3333_2222_1111);
+    test.set_x(1, data);
+    test.set_x(2, 3);
+    test.run();
+    assert_eq!(test.x(0), 0x4444);
+}
+
+#[test]
+fn q_register_round_trip() {
+    let mut test = Harness::new(&[
+        0x3d800020, // str q0, [x1]
+        0x3dc00021, // ldr q1, [x1]
+    ]);
+    let data = test.data_addr();
+    let value = 0x00112233_44556677_8899AABB_CCDDEEFF;
+    test.set_v(0, value);
+    test.set_x(1, data);
+    test.run();
+    assert_eq!(test.v(1), value);
+}
+
+#[test]
+fn mov_lanes_are_not_translated() {
+    let mut test = Harness::new(&[]);
+    for inst in [
+        0x5e0c0420, // mov s0, v1.s[1]
+        0x6e0c0420, // mov v0.s[1], v1.s[0]
+    ] {
+        let inst = bad64::decode(inst, 0).unwrap();
+        let mut asm = InstAssembler::new(utils::get_var_addr(&test.context.registers));
+        let result = emit_mov(&mut test.context, &mut asm, inst.operands());
+        assert!(
+            matches!(result, Err(Error::UnsupportedOperand(_))),
+            "{}",
+            inst
+        );
+    }
+}
+
+#[test]
+fn mov_lanes_fall_back_to_interpreter() {
+    let mut test = Harness::new(&[
+        0x5e0c0420, // mov s0, v1.s[1]
+        0x6e0c0422, // mov v2.s[1], v1.s[0]
+    ]);
+    test.set_v(1, 0x44444444_33333333_22222222_11111111);
+    test.set_v(2, u128::MAX);
+    test.run();
+    assert_eq!(test.v(0), 0x22222222);
+    assert_eq!(test.v(2), 0xffffffff_ffffffff_11111111_ffffffff);
+}
+
+#[test]
+fn store_patches_later_instruction_of_block() {
+    let mut test = Harness::new(&[
+        0xb9000023, // str w3, [x1]
+        0x91000442, // add x2, x2, #1
+        0xd2800020, // mov x0, #1
+    ]);
+    test.set_x(1, test.code_addr() + 8);
+    test.set_x(3, 0xd2800060); // mov x0, #3
+    test.run();
+    assert_eq!(test.x(0), 3);
+    assert_eq!(test.x(2), 1);
+}
+
+/// Runs a loop whose body rewrites its first instruction, so the block the
+/// branch back jumps to is dropped on every iteration.
+fn patch_loop_head(verify: bool) {
+    let mut test = Harness::new(&[
+        0xd2800062, // mov x2, #3
+        0x91000400, // add x0, x0, #1
+        0xb9000023, // str w3, [x1]
+        0xd1000442, // sub x2, x2, #1
+        0xb5ffffa2, // cbnz x2, #-12
+    ]);
+    test.context.verify = verify;
+    test.set_x(1, test.code_addr() + 4);
+    test.set_x(3, 0x91004000); // add x0, x0, #16
+    test.run();
+    assert_eq!(test.x(0), 0x21);
+    assert_eq!(test.x(2), 0);
+}
+
+#[test]
+fn loop_patches_its_head() {
+    patch_loop_head(true);
+}
+
+#[test]
+fn linked_loop_patches_its_head() {
+    patch_loop_head(false);
+}
+
+#[test]
+fn allocated_registers_survive_host_call() {
+    let mut test = Harness::new(&[
+        0x91000400, // add x0, x0, #1
+        0x91000484, // add x4, x4, #1
+        0xb9000023, // str w3, [x1]
+        0x91000400, // add x0, x0, #1
+        0x91000484, // add x4, x4, #1
+    ]);
+    // Rewriting an instruction with itself still calls into the code cache,
+    // while x0 and x4 are held in host registers
+    test.set_x(1, test.code_addr() + 12);
+    test.set_x(3, 0x91000400);
+    test.run();
+    assert_eq!(test.x(0), 2);
+    assert_eq!(test.x(4), 2);
+}
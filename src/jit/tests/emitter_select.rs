@@ -0,0 +1,61 @@
+use crate::jit::tests::{Harness, C, N, Z};
+
+#[test]
+fn csel() {
+    let code = [
+        0x9a82b020, // csel x0, x1, x2, lt
+    ];
+
+    let mut test = Harness::new(&code);
+    test.set_nzcv(N);
+    test.set_x(1, 1);
+    test.set_x(2, 2);
+    test.run();
+    assert_eq!(test.x(0), 1);
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 1);
+    test.set_x(2, 2);
+    test.run();
+    assert_eq!(test.x(0), 2);
+}
+
+#[test]
+fn cset_hi() {
+    let code = [
+        0x1a9f97e0, // cset w0, hi
+    ];
+
+    let mut test = Harness::new(&code);
+    test.set_nzcv(C);
+    test.run();
+    assert_eq!(test.x(0), 1);
+
+    let mut test = Harness::new(&code);
+    test.set_nzcv(Z | C);
+    test.run();
+    assert_eq!(test.x(0), 0);
+}
+
+#[test]
+fn csinc_increments_when_false() {
+    let mut test = Harness::new(&[
+        0x9a820420, // csinc x0, x1, x2, eq
+    ]);
+    test.set_nzcv(0);
+    test.set_x(1, 1);
+    test.set_x(2, u64::MAX);
+    test.run();
+    assert_eq!(test.x(0), 0);
+}
+
+#[test]
+fn cneg_negates_when_true() {
+    let mut test = Harness::new(&[
+        0xda815420, // cneg x0, x1, mi
+    ]);
+    test.set_nzcv(N);
+    test.set_x(1, 5);
+    test.run();
+    assert_eq!(test.x(0), 5u64.wrapping_neg());
+}
@@ -0,0 +1,49 @@
+use crate::jit::tests::{Harness, C, N, V, Z};
+
+#[test]
+fn mrs_nzcv_after_cmp() {
+    let mut test = Harness::new(&[
+        0xeb02003f, // cmp x1, x2
+        0xd53b4200, // mrs x0, nzcv
+    ]);
+    test.set_x(1, 1);
+    test.set_x(2, 1);
+    test.run();
+    assert_eq!(test.x(0), Z | C);
+}
+
+#[test]
+fn msr_nzcv() {
+    let mut test = Harness::new(&[
+        0xd51b4201, // msr nzcv, x1
+        0xd53b4200, // mrs x0, nzcv
+    ]);
+    test.set_x(1, N | V);
+    test.run();
+    assert_eq!(test.x(0), N | V);
+    assert_eq!(test.nzcv(), N | V);
+}
+
+#[test]
+fn msr_nzcv_ignores_res0_bits() {
+    let mut test = Harness::new(&[
+        0xd51b4201, // msr nzcv, x1
+        0xd53b4200, // mrs x0, nzcv
+    ]);
+    test.set_x(1, u64::MAX);
+    test.run();
+    assert_eq!(test.x(0), N | Z | C | V);
+    assert_eq!(test.nzcv(), N | Z | C | V);
+}
+
+#[test]
+fn fpcr_round_trip() {
+    let mut test = Harness::new(&[
+        0xd51b4401, // msr fpcr, x1
+        0xd53b4400, // mrs x0, fpcr
+    ]);
+    test.set_x(1, 0x400000);
+    test.run();
+    assert_eq!(test.x(0), 0x400000);
+    assert_eq!(*test.context.registers.borrow_mut_fpcr(), 0x400000);
+}
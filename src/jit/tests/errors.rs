@@ -0,0 +1,146 @@
+use crate::error::Error;
+use crate::jit::assembler::instructions_assembler::InstAssembler;
+use crate::jit::context::ExitReason;
+use crate::jit::tests::Harness;
+use crate::memory::{GuestMemory, MAX_IMAGE_SIZE};
+use iced_x86::Register;
+
+#[test]
+fn load_fault_leaves_pc_at_load() {
+    let mut test = Harness::new(&[
+        0x91000400, // add x0, x0, #1
+        0xf9400022, // ldr x2, [x1]
+        0x91000400, // add x0, x0, #1
+    ]);
+    let load_addr = test.code_addr() + 4;
+    match test.try_run() {
+        Err(Error::Instruction { pc, error, .. }) => {
+            assert_eq!(pc, load_addr);
+            match *error {
+                Error::MemoryFault(fault) => {
+                    assert_eq!(fault.addr, 0);
+                    assert_eq!(fault.size, 8);
+                }
+                error => panic!("Expected a memory fault, got {}", error),
+            }
+        }
+        result => panic!("Expected a memory fault, got {:?}", result),
+    }
+    assert_eq!(test.pc(), load_addr);
+    assert_eq!(test.x(0), 1);
+
+    // Fixing up the address resumes at the load
+    let data = test.data_addr();
+    test.write_u64(data, 0x1234);
+    test.set_x(1, data);
+    test.run();
+    assert_eq!(test.x(0), 2);
+    assert_eq!(test.x(2), 0x1234);
+}
+
+#[test]
+fn load_fault_writes_back_spilled_registers() {
+    // Seven guest registers are live across the load, more than the allocator
+    // has host registers for
+    let add = |reg: u32| 0x91000400 | reg << 5 | reg; // add xN, xN, #1
+    let mut code: Vec<u32> = (0..7).map(add).collect();
+    code.push(0xf9400107); // ldr x7, [x8]
+    code.extend((0..7).map(add));
+    let mut test = Harness::new(&code);
+    for reg in 0..7 {
+        test.set_x(reg, reg as u64 * 0x100);
+    }
+    let load_addr = test.code_addr() + 7 * 4;
+    match test.try_run() {
+        Err(Error::Instruction { pc, error, .. }) => {
+            assert_eq!(pc, load_addr);
+            assert!(matches!(*error, Error::MemoryFault(_)), "{}", error);
+        }
+        result => panic!("Expected a memory fault, got {:?}", result),
+    }
+    assert_eq!(test.pc(), load_addr);
+    for reg in 0..7 {
+        assert_eq!(test.x(reg), reg as u64 * 0x100 + 1, "x{}", reg);
+    }
+
+    test.set_x(8, test.data_addr());
+    test.run();
+    for reg in 0..7 {
+        assert_eq!(test.x(reg), reg as u64 * 0x100 + 2, "x{}", reg);
+    }
+}
+
+#[test]
+fn op_without_jit_or_interpreter() {
+    let mut test = Harness::new(&[
+        0x1ac24020, // crc32b w0, w1, w2
+    ]);
+    match test.try_run() {
+        Err(Error::Instruction { pc, inst, error }) => {
+            assert_eq!(pc, test.code_addr());
+            assert!(inst.starts_with("crc32b"), "{}", inst);
+            assert!(matches!(*error, Error::UnknownOp), "{}", error);
+        }
+        result => panic!("Expected an unknown op, got {:?}", result),
+    }
+}
+
+#[test]
+fn undefined_instruction() {
+    let mut test = Harness::new(&[
+        0xffffffff, // undefined
+    ]);
+    match test.try_run() {
+        Err(Error::Instruction { pc, inst, error }) => {
+            assert_eq!(pc, test.code_addr());
+            assert_eq!(inst, "0xffffffff");
+            assert!(matches!(*error, Error::UndefinedInstruction), "{}", error);
+        }
+        result => panic!("Expected an undefined instruction, got {:?}", result),
+    }
+}
+
+#[test]
+fn state_out_of_reach() {
+    let var = 0u64;
+    let mut asm = InstAssembler::new(0);
+    asm.emit_var_to_reg(&var, Register::RAX);
+    match asm.finalize() {
+        Err(Error::OutOfReach(addr)) => assert_eq!(addr, &var as *const u64 as u64),
+        Err(error) => panic!("Expected an unreachable address, got {}", error),
+        Ok(_) => panic!("Expected an unreachable address"),
+    }
+}
+
+#[test]
+fn invalid_block_exit() {
+    assert!(matches!(
+        ExitReason::try_from(7),
+        Err(Error::InvalidState("block exit", 7))
+    ));
+}
+
+#[test]
+fn unsupported_operand_ends_block_before_it() {
+    let mut test = Harness::new(&[
+        0x91000400, // add x0, x0, #1
+        0xd53bd061, // mrs x1, tpidrro_el0
+    ]);
+    let mrs_addr = test.code_addr() + 4;
+    // The interpreter doesn't know the register either, the add still ran
+    match test.try_run() {
+        Err(Error::Instruction { pc, inst, error }) => {
+            assert_eq!(pc, mrs_addr);
+            assert!(inst.starts_with("mrs"), "{}", inst);
+            assert!(matches!(*error, Error::UnsupportedOperand(_)), "{}", error);
+        }
+        result => panic!("Expected an unsupported operand, got {:?}", result),
+    }
+    assert_eq!(test.pc(), mrs_addr);
+    assert_eq!(test.x(0), 1);
+}
+
+#[test]
+fn oversized_image_is_rejected() {
+    assert!(GuestMemory::new(MAX_IMAGE_SIZE + 1).is_err());
+}
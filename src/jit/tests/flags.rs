@@ -0,0 +1,392 @@
+//! Randomized checks of the flag-setting ops the JIT translates against the
+//! AddWithCarry pseudocode. Set `FLAGS_SEED` to replay a run.
+
+use crate::jit::context::condition_holds;
+use crate::jit::tests::Harness;
+use bad64::Condition;
+use std::env;
+
+const CASES: usize = 48;
+const DEFAULT_SEED: u64 = 0x5EED_F1A6_5EED_F1A6;
+
+/// Values around the carry and overflow boundaries of both widths.
+const SPECIAL_VALUES: [u64; 12] = [
+    0,
+    1,
+    2,
+    0x7FFF_FFFF,
+    0x8000_0000,
+    0xFFFF_FFFF,
+    0x1_0000_0000,
+    0x7FFF_FFFF_FFFF_FFFF,
+    0x8000_0000_0000_0000,
+    0xFFFF_FFFF_8000_0000,
+    u64::MAX - 1,
+    u64::MAX,
+];
+
+/// Conditions in encoding order, AL and NV always hold and are left out.
+const CONDITIONS: [Condition; 14] = [
+    Condition::EQ,
+    Condition::NE,
+    Condition::CS,
+    Condition::CC,
+    Condition::MI,
+    Condition::PL,
+    Condition::VS,
+    Condition::VC,
+    Condition::HI,
+    Condition::LS,
+    Condition::GE,
+    Condition::LT,
+    Condition::GT,
+    Condition::LE,
+];
+
+/// xorshift64, good enough to pick operands and reproducible from its seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// Mostly values near the boundaries, where the flags tend to go wrong.
+    fn operand(&mut self) -> u64 {
+        match self.below(4) {
+            0 => self.next(),
+            1 => SPECIAL_VALUES[self.below(SPECIAL_VALUES.len() as u64) as usize],
+            _ => {
+                let special = SPECIAL_VALUES[self.below(SPECIAL_VALUES.len() as u64) as usize];
+                special.wrapping_add(self.below(5)).wrapping_sub(2)
+            }
+        }
+    }
+}
+
+/// The AddWithCarry pseudocode, returning the result and NZCV in bits 28-31.
+fn add_with_carry(x: u64, y: u64, carry: bool, bits: u32) -> (u64, u64) {
+    let uint = |value: u64| (value & (u64::MAX >> (64 - bits))) as i128;
+    let sint = |value: u64| ((value << (64 - bits)) as i64 >> (64 - bits)) as i128;
+
+    let unsigned_sum = uint(x) + uint(y) + carry as i128;
+    let signed_sum = sint(x) + sint(y) + carry as i128;
+    let result = unsigned_sum as u64 & (u64::MAX >> (64 - bits));
+
+    let n = result >> (bits - 1) & 1;
+    let z = (result == 0) as u64;
+    let c = (uint(result) != unsigned_sum) as u64;
+    let v = (sint(result) != signed_sum) as u64;
+    (result, (n << 3 | z << 2 | c << 1 | v) << 28)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Op {
+    Adds,
+    Subs,
+    Adcs,
+    Sbcs,
+    Cmn,
+    Cmp,
+    Ccmn,
+    Ccmp,
+}
+
+const OPS: [Op; 8] = [
+    Op::Adds,
+    Op::Subs,
+    Op::Adcs,
+    Op::Sbcs,
+    Op::Cmn,
+    Op::Cmp,
+    Op::Ccmn,
+    Op::Ccmp,
+];
+
+/// One instruction with its operands, in x1 and x2 or an immediate, and the
+/// flags it starts from, which ADCS and SBCS take their carry from.
+#[derive(Copy, Clone, Debug)]
+struct Case {
+    op: Op,
+    is_32bit: bool,
+    /// Immediate and whether it is shifted left by 12, replacing x2. 12 bits
+    /// wide, or 5 bits for CCMP/CCMN.
+    imm: Option<(u64, bool)>,
+    left: u64,
+    right: u64,
+    nzcv: u64,
+    /// CCMP/CCMN only, the condition and the flags taken when it fails
+    cond: usize,
+    cond_nzcv: u64,
+}
+
+impl Case {
+    fn random(rng: &mut Rng, op: Op, is_32bit: bool) -> Self {
+        let imm = match op {
+            Op::Adds | Op::Subs | Op::Cmn | Op::Cmp if rng.below(2) == 0 => {
+                let imm = match rng.below(3) {
+                    0 => 0,
+                    1 => 0xFFF,
+                    _ => rng.below(0x1000),
+                };
+                Some((imm, rng.below(2) == 0))
+            }
+            Op::Ccmn | Op::Ccmp if rng.below(2) == 0 => Some((rng.below(32), false)),
+            _ => None,
+        };
+        Case {
+            op,
+            is_32bit,
+            imm,
+            left: rng.operand(),
+            right: rng.operand(),
+            nzcv: rng.below(16) << 28,
+            cond: rng.below(CONDITIONS.len() as u64) as usize,
+            cond_nzcv: rng.below(16),
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        if self.is_32bit {
+            32
+        } else {
+            64
+        }
+    }
+
+    fn right_operand(&self) -> u64 {
+        match self.imm {
+            Some((imm, true)) => imm << 12,
+            Some((imm, false)) => imm,
+            None => self.right,
+        }
+    }
+
+    fn writes_result(&self) -> bool {
+        matches!(self.op, Op::Adds | Op::Subs | Op::Adcs | Op::Sbcs)
+    }
+
+    fn carry(&self) -> bool {
+        self.nzcv & 1 << 29 != 0
+    }
+
+    /// Encodes the instruction with x0 as destination, x1 as first and x2 as
+    /// second operand.
+    fn encode(&self) -> u32 {
+        let sf = (!self.is_32bit as u32) << 31;
+        let rd = if self.writes_result() { 0 } else { 31 };
+        let subtract = matches!(self.op, Op::Subs | Op::Sbcs | Op::Cmp | Op::Ccmp) as u32;
+        match (self.op, self.imm) {
+            (Op::Ccmn | Op::Ccmp, imm) => {
+                // The immediate form has bit 11 set and takes the place of x2
+                let (imm_form, right) = match imm {
+                    Some((imm, _)) => (1, imm as u32),
+                    None => (0, 2),
+                };
+                sf | subtract << 30
+                    | 0x3a400000
+                    | right << 16
+                    | (self.cond as u32) << 12
+                    | imm_form << 11
+                    | 1 << 5
+                    | self.cond_nzcv as u32
+            }
+            (Op::Adcs | Op::Sbcs, _) => sf | subtract << 30 | 0x3a000000 | 2 << 16 | 1 << 5 | rd,
+            (_, Some((imm, shifted))) => {
+                sf | subtract << 30
+                    | 0x31000000
+                    | (shifted as u32) << 22
+                    | (imm as u32) << 10
+                    | 1 << 5
+                    | rd
+            }
+            (_, None) => sf | subtract << 30 | 0x2b000000 | 2 << 16 | 1 << 5 | rd,
+        }
+    }
+
+    /// The instruction followed by a CSET into w4-w17 for every condition,
+    /// which reads the flags from the recorded operation, and an MRS into x3,
+    /// which materializes them.
+    fn code(&self) -> Vec<u32> {
+        let mut code = vec![self.encode()];
+        for (index, _) in CONDITIONS.iter().enumerate() {
+            // CSINC wd, wzr, wzr, invert(cond)
+            code.push(0x1a9f07e0 | ((index as u32) ^ 1) << 12 | (4 + index as u32));
+        }
+        code.push(0xd53b4203); // mrs x3, nzcv
+        code
+    }
+
+    /// Result and NZCV according to the pseudocode.
+    fn expected(&self) -> (u64, u64) {
+        let (left, right) = (self.left, self.right_operand());
+        match self.op {
+            Op::Adds | Op::Cmn => add_with_carry(left, right, false, self.bits()),
+            Op::Subs | Op::Cmp => add_with_carry(left, !right, true, self.bits()),
+            Op::Adcs => add_with_carry(left, right, self.carry(), self.bits()),
+            Op::Sbcs => add_with_carry(left, !right, self.carry(), self.bits()),
+            Op::Ccmn | Op::Ccmp if !condition_holds(CONDITIONS[self.cond], self.nzcv) => {
+                (0, self.cond_nzcv << 28)
+            }
+            Op::Ccmn => add_with_carry(left, right, false, self.bits()),
+            Op::Ccmp => add_with_carry(left, !right, true, self.bits()),
+        }
+    }
+
+    /// Runs the case through the JIT, describing the first mismatch.
+    fn check(&self) -> Result<(), String> {
+        let mut test = Harness::new(&self.code());
+        // Compared against the model directly
+        test.context.verify = false;
+        test.set_nzcv(self.nzcv);
+        test.set_x(1, self.left);
+        test.set_x(2, self.right);
+        test.run();
+
+        let (result, nzcv) = self.expected();
+        if self.writes_result() && test.x(0) != result {
+            return Err(format!("x0 = {:#x}, expected {:#x}", test.x(0), result));
+        }
+        for (index, cond) in CONDITIONS.iter().enumerate() {
+            let holds = condition_holds(*cond, nzcv) as u64;
+            if test.x(4 + index) != holds {
+                return Err(format!(
+                    "cset {:?} = {}, expected {} for nzcv {:#x}",
+                    cond,
+                    test.x(4 + index),
+                    holds,
+                    nzcv
+                ));
+            }
+        }
+        if test.x(3) != nzcv {
+            return Err(format!("mrs nzcv = {:#x}, expected {:#x}", test.x(3), nzcv));
+        }
+        Ok(())
+    }
+
+    /// Simpler variants of the case, each with a single field made smaller.
+    fn simplifications(&self) -> Vec<Case> {
+        let mut cases = Vec::new();
+        for value in smaller(self.left) {
+            cases.push(Case {
+                left: value,
+                ..*self
+            });
+        }
+        for value in smaller(self.right) {
+            cases.push(Case {
+                right: value,
+                ..*self
+            });
+        }
+        // Only the NZCV nibble is shrunk, the other bits are RES0
+        for value in smaller(self.nzcv >> 28) {
+            cases.push(Case {
+                nzcv: value << 28,
+                ..*self
+            });
+        }
+        if let Some((imm, shifted)) = self.imm {
+            for value in smaller(imm) {
+                cases.push(Case {
+                    imm: Some((value, shifted)),
+                    ..*self
+                });
+            }
+            if shifted {
+                cases.push(Case {
+                    imm: Some((imm, false)),
+                    ..*self
+                });
+            }
+        }
+        cases
+    }
+
+    /// Shrinks a failing case until no simplification fails anymore.
+    fn shrink(mut self) -> (Case, String) {
+        let mut error = self.check().unwrap_err();
+        'shrink: loop {
+            for case in self.simplifications() {
+                if let Err(case_error) = case.check() {
+                    self = case;
+                    error = case_error;
+                    continue 'shrink;
+                }
+            }
+            return (self, error);
+        }
+    }
+
+    /// A harness test reproducing the case.
+    fn reproducer(&self) -> String {
+        let inst = bad64::decode(self.encode(), 0).unwrap();
+        let mut code = format!(
+            "let mut test = Harness::new(&[\n    {:#010x}, // {}\n]);\n",
+            self.encode(),
+            inst
+        );
+        code += &format!("test.set_nzcv({:#x});\n", self.nzcv);
+        code += &format!("test.set_x(1, {:#x});\n", self.left);
+        if self.imm.is_none() {
+            code += &format!("test.set_x(2, {:#x});\n", self.right);
+        }
+        code += "test.run();\n";
+        let (result, nzcv) = self.expected();
+        if self.writes_result() {
+            code += &format!("assert_eq!(test.x(0), {:#x});\n", result);
+        }
+        code += &format!("assert_eq!(test.nzcv(), {:#x});\n", nzcv);
+        code
+    }
+}
+
+/// Candidates below `value`, from the simplest.
+fn smaller(value: u64) -> Vec<u64> {
+    let top_bit = 63 - value.leading_zeros().min(63);
+    let mut values = vec![
+        0,
+        1,
+        value >> 1,
+        value & (value.wrapping_sub(1)),
+        value & !(1 << top_bit),
+        value.wrapping_sub(1),
+    ];
+    values.retain(|&candidate| candidate < value);
+    values.dedup();
+    values
+}
+
+#[test]
+fn flag_setting_ops_match_add_with_carry() {
+    let seed = match env::var("FLAGS_SEED") {
+        Ok(seed) => u64::from_str_radix(seed.trim_start_matches("0x"), 16).unwrap(),
+        Err(_) => DEFAULT_SEED,
+    };
+    let mut rng = Rng(seed);
+
+    for op in OPS {
+        for is_32bit in [false, true] {
+            for _ in 0..CASES {
+                let case = Case::random(&mut rng, op, is_32bit);
+                if case.check().is_err() {
+                    let (case, error) = case.shrink();
+                    panic!(
+                        "Flags diverge from AddWithCarry with seed {:#x}: {}\nMinimal reproducer:\n{}",
+                        seed,
+                        error,
+                        case.reproducer()
+                    );
+                }
+            }
+        }
+    }
+}
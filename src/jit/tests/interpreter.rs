@@ -0,0 +1,100 @@
+use crate::error::Error;
+use crate::jit::tests::Harness;
+
+#[test]
+fn op_without_emitter_between_blocks() {
+    let mut test = Harness::new(&[
+        0xd2800020, // mov x0, #1
+        0xf2b7dde0, // movk x0, #0xbeef, lsl #16
+        0x8b010000, // add x0, x0, x1
+    ]);
+    test.set_x(1, 2);
+    test.run();
+    assert_eq!(test.x(0), 0xBEEF0003);
+}
+
+#[test]
+fn udiv_by_zero() {
+    let code = [
+        0x9ac20820, // udiv x0, x1, x2
+    ];
+
+    let mut test = Harness::new(&code);
+    test.set_x(1, 100);
+    test.set_x(2, 7);
+    test.run();
+    assert_eq!(test.x(0), 14);
+
+    let mut test = Harness::new(&code);
+    test.set_x(0, 1);
+    test.set_x(1, 100);
+    test.run();
+    assert_eq!(test.x(0), 0);
+}
+
+const V1: u128 = 0x44444444_33333333_22222222_11111111;
+
+#[test]
+fn mov_lane_to_scalar() {
+    let mut test = Harness::new(&[
+        0x5e0c0420, // mov s0, v1.s[1]
+    ]);
+    test.set_v(0, u128::MAX);
+    test.set_v(1, V1);
+    test.run();
+    assert_eq!(test.v(0), 0x22222222);
+}
+
+#[test]
+fn mov_lane_to_lane() {
+    let mut test = Harness::new(&[
+        0x6e0c0420, // mov v0.s[1], v1.s[0]
+    ]);
+    test.set_v(0, 0xdddddddd_cccccccc_bbbbbbbb_aaaaaaaa);
+    test.set_v(1, V1);
+    test.run();
+    assert_eq!(test.v(0), 0xdddddddd_cccccccc_11111111_aaaaaaaa);
+}
+
+#[test]
+fn mov_between_lanes_and_gprs() {
+    let mut test = Harness::new(&[
+        0x0e0c3c20, // mov w0, v1.s[1]
+        0x4e0c1c22, // mov v2.s[1], w1
+    ]);
+    test.set_x(1, 0x55555555_66666666);
+    test.set_v(1, V1);
+    test.set_v(2, u128::MAX);
+    test.run();
+    assert_eq!(test.x(0), 0x22222222);
+    assert_eq!(test.v(2), 0xffffffff_ffffffff_66666666_ffffffff);
+}
+
+#[test]
+fn mov_arrangement_clears_upper_half() {
+    let mut test = Harness::new(&[
+        0x0ea11c20, // mov v0.8b, v1.8b
+    ]);
+    test.set_v(0, u128::MAX);
+    test.set_v(1, V1);
+    test.run();
+    assert_eq!(test.v(0), 0x22222222_11111111);
+}
+
+#[test]
+fn vector_forms_without_interpreter_support() {
+    for inst in [
+        0x4ea29c20, // mul v0.4s, v1.4s, v2.4s
+        0x6ea04820, // clz v0.4s, v1.4s
+        0x4ea28420, // add v0.4s, v1.4s, v2.4s
+    ] {
+        let mut test = Harness::new(&[inst]);
+        match test.try_run() {
+            Err(Error::Instruction { error, .. }) => {
+                assert!(matches!(*error, Error::UnsupportedOperand(_)), "{}", error)
+            }
+            result => panic!("Expected an unsupported operand, got {:?}", result),
+        }
+        assert_eq!(test.pc(), test.code_addr());
+    }
+}
@@ -0,0 +1,114 @@
+mod code_cache;
+mod emitter_arithmetic;
+mod emitter_bit;
+mod emitter_branch;
+mod emitter_cmp;
+mod emitter_mem;
+mod emitter_select;
+mod emitter_sys;
+mod errors;
+mod flags;
+mod interpreter;
+mod parser;
+mod verifier;
+
+use crate::error::Result;
+use crate::jit::context::{Context, ExitReason, SP_SLOT};
+use crate::memory::{GuestMemory, LOAD_BASE, PAGE_SIZE};
+
+pub const N: u64 = 1 << 31;
+pub const Z: u64 = 1 << 30;
+pub const C: u64 = 1 << 29;
+pub const V: u64 = 1 << 28;
+
+/// Runs raw AArch64 instruction words through the JIT, starting from the
+/// register, flag and memory state the test sets up.
+///
+/// The code sits at the top of the guest address space, so running past its
+/// last instruction ends the run. The stack starts a page below it. NZCV
+/// starts out with Z set, as the guest does. Every block is checked against
+/// the interpreter as well.
+pub struct Harness {
+    pub context: Context,
+    code_addr: u64,
+}
+
+impl Harness {
+    pub fn new(code: &[u32]) -> Self {
+        let mut memory = GuestMemory::new(PAGE_SIZE).unwrap();
+        let code_addr = LOAD_BASE + memory.size() - code.len() as u64 * 4;
+        for (index, inst) in code.iter().enumerate() {
+            memory
+                .write_u32(code_addr + index as u64 * 4, *inst)
+                .unwrap();
+        }
+        let stack_top = memory.stack_top() - PAGE_SIZE;
+
+        let mut context = Context::new(memory, code_addr).unwrap();
+        *context.registers.borrow_mut_gpr(SP_SLOT) = stack_top;
+        context.verify = true;
+        Harness { context, code_addr }
+    }
+
+    /// Guest address of the first instruction.
+    pub fn code_addr(&self) -> u64 {
+        self.code_addr
+    }
+
+    /// Guest address of memory free for the test to use, the start of the heap.
+    pub fn data_addr(&self) -> u64 {
+        self.context.memory.heap().start
+    }
+
+    /// Runs the code and asserts that it ran past its last instruction.
+    pub fn run(&mut self) {
+        assert_eq!(self.try_run().unwrap(), ExitReason::EndOfCode);
+    }
+
+    /// Runs the code, leaving failures to the test.
+    pub fn try_run(&mut self) -> Result<ExitReason> {
+        self.context.run()
+    }
+
+    pub fn x(&mut self, index: usize) -> u64 {
+        assert!(index < SP_SLOT);
+        *self.context.registers.borrow_mut_gpr(index)
+    }
+
+    pub fn set_x(&mut self, index: usize, value: u64) {
+        assert!(index < SP_SLOT);
+        *self.context.registers.borrow_mut_gpr(index) = value;
+    }
+
+    pub fn sp(&mut self) -> u64 {
+        *self.context.registers.borrow_mut_gpr(SP_SLOT)
+    }
+
+    pub fn pc(&mut self) -> u64 {
+        *self.context.registers.borrow_mut_pc()
+    }
+
+    pub fn nzcv(&mut self) -> u64 {
+        self.context.registers.nzcv.value().unwrap()
+    }
+
+    pub fn set_nzcv(&mut self, value: u64) {
+        self.context.registers.nzcv.set(value);
+    }
+
+    pub fn v(&mut self, index: usize) -> u128 {
+        *self.context.registers.borrow_mut_vslot(index)
+    }
+
+    pub fn set_v(&mut self, index: usize, value: u128) {
+        *self.context.registers.borrow_mut_vslot(index) = value;
+    }
+
+    pub fn read_u64(&self, addr: u64) -> u64 {
+        self.context.memory.read_u64(addr).unwrap()
+    }
+
+    pub fn write_u64(&mut self, addr: u64, value: u64) {
+        self.context.memory.write_u64(addr, value).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+use crate::jit::parser::parse_inst;
+
+#[test]
+fn vector_forms_are_left_to_interpreter() {
+    for inst in [
+        0x4ea28420, // add v0.4s, v1.4s, v2.4s
+        0x4ea21c20, // orr v0.16b, v1.16b, v2.16b
+        0x5e0c0420, // mov s0, v1.s[1]
+        0x6e0c0420, // mov v0.s[1], v1.s[0]
+    ] {
+        let (inst, info) = parse_inst(0, inst).unwrap();
+        assert!(info.is_none(), "{} has an emitter", inst);
+    }
+}
+
+#[test]
+fn scalar_forms_are_translated() {
+    for inst in [
+        0x8b020020, // add x0, x1, x2
+        0xaa0203e0, // mov x0, x2
+        0x3d800020, // str q0, [x1]
+    ] {
+        let (inst, info) = parse_inst(0, inst).unwrap();
+        assert!(info.is_some(), "{} has no emitter", inst);
+    }
+}
+
+#[test]
+fn unknown_system_registers_are_left_to_interpreter() {
+    for (inst, translated) in [
+        (0xd53b4200, true),  // mrs x0, nzcv
+        (0xd51b4400, true),  // msr fpcr, x0
+        (0xd53bd060, false), // mrs x0, tpidrro_el0
+        (0xd51bd040, false), // msr tpidr_el0, x0
+    ] {
+        let (inst, info) = parse_inst(0, inst).unwrap();
+        assert_eq!(info.is_some(), translated, "{}", inst);
+    }
+}
@@ -0,0 +1,52 @@
+use crate::jit::tests::Harness;
+use crate::jit::verifier::{Location, Reference};
+
+#[test]
+fn swapped_lanes_diverge() {
+    let mut test = Harness::new(&[
+        0x3dc00021, // ldr q1, [x1]
+    ]);
+    let data = test.data_addr();
+    test.write_u64(data, 0x22222222_11111111);
+    test.write_u64(data + 8, 0x44444444_33333333);
+    test.set_x(1, data);
+    let end = test.code_addr() + 4;
+    let reference = Reference::record(&test.context.registers, &mut test.context.memory, end)
+        .unwrap()
+        .unwrap();
+
+    // What a JIT mixing up the two halves of the load would leave
+    test.set_v(1, 0x22222222_11111111_44444444_33333333);
+    *test.context.registers.borrow_mut_pc() += 4;
+    let divergence = reference
+        .check(&mut test.context.registers, &test.context.memory)
+        .unwrap()
+        .unwrap();
+    assert_eq!(divergence.location, Location::Vreg(1));
+    assert_eq!(
+        divergence.inst,
+        Some((test.code_addr(), String::from("ldr q1, [x1]")))
+    );
+}
+
+#[test]
+fn matching_lanes_pass() {
+    let mut test = Harness::new(&[
+        0x3dc00021, // ldr q1, [x1]
+    ]);
+    let data = test.data_addr();
+    test.write_u64(data, 0x22222222_11111111);
+    test.write_u64(data + 8, 0x44444444_33333333);
+    test.set_x(1, data);
+    let end = test.code_addr() + 4;
+    let reference = Reference::record(&test.context.registers, &mut test.context.memory, end)
+        .unwrap()
+        .unwrap();
+
+    test.set_v(1, 0x44444444_33333333_22222222_11111111);
+    *test.context.registers.borrow_mut_pc() += 4;
+    assert!(reference
+        .check(&mut test.context.registers, &test.context.memory)
+        .unwrap()
+        .is_none());
+}
@@ -1,5 +1,6 @@
-use crate::jit::assembler::instructions_assembler::InstAssembler;
-use crate::jit::assembler::registers_handler::RegistersHandler;
+use crate::error::{Error, Result};
+use crate::jit::assembler::instructions_assembler::{CallArg, InstAssembler};
+use crate::jit::assembler::registers_handler::{map_reg_32, SCRATCH_REGISTER, STATE_REGISTER};
 use iced_x86::{Code, Instruction, MemoryOperand, Register};
 
 macro_rules! get_fn_addr {
@@ -10,6 +11,10 @@ macro_rules! get_fn_addr {
 use crate::jit::utils::private::EmitSetVar;
 pub(crate) use get_fn_addr;
 
+fn unsupported_vector_size(size: usize) -> Error {
+    Error::UnsupportedOperand(format!("{}-byte vector access", size))
+}
+
 pub fn get_var_addr<T>(var: &T) -> u64 {
     (var as *const T) as u64
 }
@@ -21,23 +26,96 @@ mod private {
 }
 
 impl InstAssembler {
+    /// Memory operand of `var`, which lives in the guest state block.
+    fn state_mem<T>(&mut self, var: &T, displ: i64) -> MemoryOperand {
+        let offset = self.state_offset(get_var_addr(var));
+        MemoryOperand::with_base_displ(STATE_REGISTER, offset + displ)
+    }
+
+    /// Host call argument passing the address of `var`. `var` must be at a
+    /// fixed offset from the guest state, like the `Context` holding it.
+    pub fn var_arg<T>(&mut self, var: &T) -> CallArg {
+        CallArg::State(self.state_offset(get_var_addr(var)))
+    }
+
     pub fn emit_var_to_reg(&mut self, var: &u64, reg: Register) {
+        let mem = self.state_mem(var, 0);
+        self.uw_add(Instruction::with2(Code::Mov_r64_rm64, reg, mem));
+    }
+
+    pub fn emit_var_to_reg32(&mut self, var: &u64, reg: Register) {
+        let mem = self.state_mem(var, 0);
         self.uw_add(Instruction::with2(
-            Code::Mov_r64_imm64,
-            reg,
-            get_var_addr(var),
+            Code::Mov_r32_rm32,
+            map_reg_32(&reg),
+            mem,
         ));
+    }
+
+    /// Stores the low 32 bits of `src` into `dest` and clears the upper half.
+    pub fn emit_set_var32(&mut self, src: Register, dest: &mut u64) {
+        let low = self.state_mem(dest, 0);
+        let high = self.state_mem(dest, 4);
         self.uw_add(Instruction::with2(
-            Code::Mov_r64_rm64,
-            reg,
-            MemoryOperand::with_base(reg),
+            Code::Mov_rm32_r32,
+            low,
+            map_reg_32(&src),
         ));
+        self.uw_add(Instruction::with2(Code::Mov_rm32_imm32, high, 0));
+    }
+
+    /// Loads the low `size` bytes of a vector variable into `xmm`, zeroing the rest.
+    pub fn emit_var_to_xmm(&mut self, var: &u128, xmm: Register, size: usize) -> Result<()> {
+        let scratch_32 = map_reg_32(&SCRATCH_REGISTER);
+
+        let mem = self.state_mem(var, 0);
+        match size {
+            1 => {
+                self.uw_add(Instruction::with2(Code::Movzx_r32_rm8, scratch_32, mem));
+                self.uw_add(Instruction::with2(Code::Movd_xmm_rm32, xmm, scratch_32));
+            }
+            2 => {
+                self.uw_add(Instruction::with2(Code::Movzx_r32_rm16, scratch_32, mem));
+                self.uw_add(Instruction::with2(Code::Movd_xmm_rm32, xmm, scratch_32));
+            }
+            4 => self.uw_add(Instruction::with2(Code::Movd_xmm_rm32, xmm, mem)),
+            8 => self.uw_add(Instruction::with2(Code::Movq_xmm_xmmm64, xmm, mem)),
+            16 => self.uw_add(Instruction::with2(Code::Movdqu_xmm_xmmm128, xmm, mem)),
+            _ => return Err(unsupported_vector_size(size)),
+        }
+        Ok(())
+    }
+
+    /// Stores the low `size` bytes of `xmm` into a vector variable and clears the rest.
+    pub fn emit_set_xmm_var(&mut self, xmm: Register, dest: &mut u128, size: usize) -> Result<()> {
+        if !matches!(size, 1 | 2 | 4 | 8 | 16) {
+            return Err(unsupported_vector_size(size));
+        }
+        let mem = self.state_mem(dest, 0);
+        if size < 16 {
+            self.uw_add(Instruction::with2(Code::Mov_rm64_imm32, mem, 0));
+            let high = self.state_mem(dest, 8);
+            self.uw_add(Instruction::with2(Code::Mov_rm64_imm32, high, 0));
+        }
+        match size {
+            1 => self.uw_add(Instruction::with3(Code::Pextrb_r32m8_xmm_imm8, mem, xmm, 0)),
+            2 => self.uw_add(Instruction::with3(
+                Code::Pextrw_r32m16_xmm_imm8,
+                mem,
+                xmm,
+                0,
+            )),
+            4 => self.uw_add(Instruction::with2(Code::Movd_rm32_xmm, mem, xmm)),
+            8 => self.uw_add(Instruction::with2(Code::Movq_xmmm64_xmm, mem, xmm)),
+            _ => self.uw_add(Instruction::with2(Code::Movdqu_xmmm128_xmm, mem, xmm)),
+        }
+        Ok(())
     }
 
     #[inline]
     pub fn emit_set_var<T>(&mut self, src: T, dest: &mut u64)
-        where
-            Self: EmitSetVar<T>,
+    where
+        Self: EmitSetVar<T>,
     {
         (self as &mut dyn EmitSetVar<T>).emit_set_var(src, dest);
     }
@@ -45,40 +123,20 @@ impl InstAssembler {
 
 impl EmitSetVar<u64> for InstAssembler {
     fn emit_set_var(&mut self, src: u64, dest: &mut u64) {
-        let mut regs_handler = RegistersHandler::new();
-        let addr_reg = regs_handler.get_free().unwrap();
-        let value_reg = regs_handler.get_free().unwrap();
-
-        self.uw_add(Instruction::with2(
-            Code::Mov_r64_imm64,
-            addr_reg,
-            get_var_addr(dest),
-        ));
-        self.uw_add(Instruction::with2(Code::Mov_r64_imm64, value_reg, src));
+        let low = self.state_mem(dest, 0);
+        let high = self.state_mem(dest, 4);
+        self.uw_add(Instruction::with2(Code::Mov_rm32_imm32, low, src as u32));
         self.uw_add(Instruction::with2(
-            Code::Mov_rm64_r64,
-            MemoryOperand::with_base(addr_reg),
-            value_reg,
+            Code::Mov_rm32_imm32,
+            high,
+            (src >> 32) as u32,
         ));
     }
 }
 
 impl EmitSetVar<Register> for InstAssembler {
     fn emit_set_var(&mut self, src: Register, dest: &mut u64) {
-        let mut regs_handler = RegistersHandler::new();
-        regs_handler.reserve(src);
-
-        let addr_reg = regs_handler.get_free().unwrap();
-
-        self.uw_add(Instruction::with2(
-            Code::Mov_r64_imm64,
-            addr_reg,
-            get_var_addr(dest),
-        ));
-        self.uw_add(Instruction::with2(
-            Code::Mov_rm64_r64,
-            MemoryOperand::with_base(addr_reg),
-            src,
-        ));
+        let mem = self.state_mem(dest, 0);
+        self.uw_add(Instruction::with2(Code::Mov_rm64_r64, mem, src));
     }
 }
@@ -0,0 +1,290 @@
+use crate::error::Result;
+use crate::jit::context::{Registers, SP_SLOT, VREG_COUNT};
+use crate::jit::interpreter::{self, MemoryWrite};
+use crate::jit::parser::inst_info;
+use crate::memory::GuestMemory;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Formatter;
+
+/// A piece of guest state compared between the JIT and the interpreter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// General-purpose register slot, as given by `gpr_slot`
+    Gpr(usize),
+    Pc,
+    Nzcv,
+    Vreg(usize),
+    Fpcr,
+    Fpsr,
+    Memory {
+        addr: u64,
+        size: usize,
+    },
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::Gpr(SP_SLOT) => write!(f, "sp"),
+            Location::Gpr(slot) => write!(f, "x{}", slot),
+            Location::Pc => write!(f, "pc"),
+            Location::Nzcv => write!(f, "nzcv"),
+            Location::Vreg(index) => write!(f, "v{}", index),
+            Location::Fpcr => write!(f, "fpcr"),
+            Location::Fpsr => write!(f, "fpsr"),
+            Location::Memory { addr, size } => {
+                write!(f, "memory {:#x}..{:#x}", addr, addr + *size as u64)
+            }
+        }
+    }
+}
+
+/// Every register of `registers`, always in the same order.
+fn snapshot(registers: &mut Registers) -> Result<Vec<(Location, u128)>> {
+    let mut state = Vec::new();
+    for slot in 0..=SP_SLOT {
+        let value = *registers.borrow_mut_gpr(slot);
+        state.push((Location::Gpr(slot), value as u128));
+    }
+    state.push((Location::Pc, *registers.borrow_mut_pc() as u128));
+    state.push((Location::Nzcv, registers.nzcv.value()? as u128));
+    for index in 0..VREG_COUNT {
+        state.push((Location::Vreg(index), *registers.borrow_mut_vslot(index)));
+    }
+    state.push((Location::Fpcr, *registers.borrow_mut_fpcr() as u128));
+    state.push((Location::Fpsr, *registers.borrow_mut_fpsr() as u128));
+    Ok(state)
+}
+
+/// An instruction of the block as the interpreter executed it.
+struct Step {
+    pc: u64,
+    inst: String,
+    /// Registers after the instruction
+    registers: Vec<(Location, u128)>,
+    write: Option<MemoryWrite>,
+}
+
+/// Where the JIT's run of a block first differs from the interpreter's.
+#[derive(Debug)]
+pub struct Divergence {
+    /// Guest address of the block
+    pub block: u64,
+    pub location: Location,
+    pub jit: String,
+    pub interpreter: String,
+    /// Address and disassembly of the last instruction of the block writing
+    /// `location`, `None` if the block leaves it alone
+    pub inst: Option<(u64, String)>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Block {:#x} diverged in {}: JIT {}, interpreter {}",
+            self.block, self.location, self.jit, self.interpreter
+        )?;
+        match &self.inst {
+            Some((pc, inst)) => write!(f, ", written by {:#x}: {}", pc, inst),
+            None => write!(f, ", which the block doesn't write"),
+        }
+    }
+}
+
+/// The interpreter's run of a block, which the JIT's run from the same state
+/// is checked against.
+pub struct Reference {
+    block: u64,
+    /// Registers before the block
+    start: Vec<(Location, u128)>,
+    steps: Vec<Step>,
+    /// Bytes the interpreter left at each write once the block was done,
+    /// together with the index of the step writing them
+    written: Vec<(usize, u64, Vec<u8>)>,
+}
+
+impl Reference {
+    /// Interprets the block at the guest PC on a copy of `registers` and then
+    /// undoes its stores, leaving `memory` as it found it. The block ends
+    /// where the JIT ends it, at `end` at the latest, so `memory` must already
+    /// mark the pages of the block as holding code. Returns `None` if the
+    /// block faults, which the JIT reports itself.
+    pub fn record(
+        registers: &Registers,
+        memory: &mut GuestMemory,
+        end: u64,
+    ) -> Result<Option<Self>> {
+        let mut registers = registers.clone();
+        let block = *registers.borrow_mut_pc();
+        let start = match snapshot(&mut registers) {
+            Ok(start) => start,
+            Err(_) => return Ok(None),
+        };
+        let mut steps: Vec<Step> = Vec::new();
+
+        let faulted = loop {
+            let pc = *registers.borrow_mut_pc();
+            if pc == end {
+                break false;
+            }
+            let inst = match memory
+                .read_u32(pc)
+                .ok()
+                .and_then(|word| bad64::decode(word, pc).ok())
+            {
+                Some(inst) => inst,
+                None => break false,
+            };
+            // The JIT leaves the instruction to the interpreter after the block
+            let info = match inst_info(&inst) {
+                Some(info) => info,
+                None => break false,
+            };
+
+            let write = match interpreter::step(&mut registers, memory) {
+                Ok(write) => write,
+                Err(_) => break true,
+            };
+            // Stores to code leave the block right after them
+            let code_write = match &write {
+                Some(write) => memory.has_code_pages(write.range()),
+                None => false,
+            };
+            let snapshot = match snapshot(&mut registers) {
+                Ok(snapshot) => snapshot,
+                Err(_) => break true,
+            };
+            steps.push(Step {
+                pc,
+                inst: inst.to_string(),
+                registers: snapshot,
+                write,
+            });
+            if info.ends_block || code_write {
+                break false;
+            }
+        };
+
+        let mut written = Vec::new();
+        for (index, step) in steps.iter().enumerate() {
+            if let Some(write) = &step.write {
+                let mut bytes = vec![0; write.previous.len()];
+                memory.read_bytes(write.addr, &mut bytes)?;
+                written.push((index, write.addr, bytes));
+            }
+        }
+        for write in steps.iter().rev().filter_map(|step| step.write.as_ref()) {
+            memory.write_bytes(write.addr, &write.previous)?;
+        }
+
+        if faulted {
+            return Ok(None);
+        }
+        Ok(Some(Reference {
+            block,
+            start,
+            steps,
+            written,
+        }))
+    }
+
+    /// Compares the state the JIT left after running the block against the
+    /// interpreter's. Among several differences, the one written earliest in
+    /// the block is returned. Only memory the interpreter wrote is compared,
+    /// stray stores of the JIT elsewhere go unnoticed.
+    pub fn check(
+        &self,
+        registers: &mut Registers,
+        memory: &GuestMemory,
+    ) -> Result<Option<Divergence>> {
+        let mut divergences = Vec::new();
+
+        let jit_state = snapshot(registers)?;
+        let expected = match self.steps.last() {
+            Some(step) => &step.registers,
+            None => &self.start,
+        };
+        for (index, (&(location, jit), &(_, interpreter))) in
+            jit_state.iter().zip(expected).enumerate()
+        {
+            if jit != interpreter {
+                let step = self.last_register_write(index);
+                divergences.push((
+                    step,
+                    self.divergence(
+                        location,
+                        format!("{:#x}", jit),
+                        format!("{:#x}", interpreter),
+                        step,
+                    ),
+                ));
+            }
+        }
+
+        // Bytes written more than once are blamed on the last write
+        let mut owners = HashMap::new();
+        for (index, (_, addr, bytes)) in self.written.iter().enumerate() {
+            for offset in 0..bytes.len() as u64 {
+                owners.insert(addr + offset, index);
+            }
+        }
+        for (index, (step, addr, bytes)) in self.written.iter().enumerate() {
+            let mut jit = vec![0; bytes.len()];
+            memory.read_bytes(*addr, &mut jit)?;
+            let differs = (0..bytes.len()).any(|offset| {
+                jit[offset] != bytes[offset] && owners[&(addr + offset as u64)] == index
+            });
+            if differs {
+                let location = Location::Memory {
+                    addr: *addr,
+                    size: bytes.len(),
+                };
+                divergences.push((
+                    Some(*step),
+                    self.divergence(
+                        location,
+                        format!("{:02x?}", jit),
+                        format!("{:02x?}", bytes),
+                        Some(*step),
+                    ),
+                ));
+            }
+        }
+
+        Ok(divergences
+            .into_iter()
+            .min_by_key(|(step, _)| step.unwrap_or(usize::MAX))
+            .map(|(_, divergence)| divergence))
+    }
+
+    /// Index of the last step changing the register at `index` of a snapshot.
+    fn last_register_write(&self, index: usize) -> Option<usize> {
+        let mut before = &self.start;
+        let mut last = None;
+        for (step_index, step) in self.steps.iter().enumerate() {
+            if step.registers[index] != before[index] {
+                last = Some(step_index);
+            }
+            before = &step.registers;
+        }
+        last
+    }
+
+    fn divergence(
+        &self,
+        location: Location,
+        jit: String,
+        interpreter: String,
+        step: Option<usize>,
+    ) -> Divergence {
+        Divergence {
+            block: self.block,
+            location,
+            jit,
+            interpreter,
+            inst: step.map(|step| (self.steps[step].pc, self.steps[step].inst.clone())),
+        }
+    }
+}
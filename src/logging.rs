@@ -0,0 +1,162 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Environment variable holding a log spec, see `configure`.
+pub const LOG_ENV: &str = "SHITJIT_LOG";
+
+/// How detailed a message is. A category logs messages up to its level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Info = 1,
+    Debug = 2,
+    Trace = 3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// Guest instructions of translated blocks
+    Guest,
+    /// Host code of translated blocks
+    Host,
+    /// Blocks being compiled, looked up and interpreted
+    Block,
+    /// Guest register dumps
+    Regs,
+    /// Supervisor calls made by the guest
+    Svc,
+}
+
+const CATEGORIES: [(&str, Category); 5] = [
+    ("guest", Category::Guest),
+    ("host", Category::Host),
+    ("block", Category::Block),
+    ("regs", Category::Regs),
+    ("svc", Category::Svc),
+];
+
+const LEVELS: [(&str, Level); 3] = [
+    ("info", Level::Info),
+    ("debug", Level::Debug),
+    ("trace", Level::Trace),
+];
+
+/// Level of each category, 0 if it is off.
+static ENABLED: [AtomicU8; CATEGORIES.len()] = [
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+    AtomicU8::new(0),
+];
+
+pub fn enabled(category: Category, level: Level) -> bool {
+    ENABLED[category as usize].load(Ordering::Relaxed) >= level as u8
+}
+
+pub fn set_level(category: Category, level: Option<Level>) {
+    let level = level.map_or(0, |level| level as u8);
+    ENABLED[category as usize].store(level, Ordering::Relaxed);
+}
+
+/// Enables logging from a comma separated spec. Each entry is
+/// `category[=level]`, `all[=level]` or a bare level applying to every
+/// category, the level defaults to trace. Categories are guest, host, block,
+/// regs and svc, levels are info, debug and trace, or off. Later entries win.
+pub fn configure(spec: &str) -> Result<(), String> {
+    for entry in spec
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (name, level) = match entry.split_once('=') {
+            Some((name, level)) => (name, parse_level(level)?),
+            None => match parse_level(entry) {
+                Ok(level) => ("all", level),
+                Err(_) => (entry, Some(Level::Trace)),
+            },
+        };
+        if name == "all" {
+            for (_, category) in CATEGORIES {
+                set_level(category, level);
+            }
+        } else {
+            let category = CATEGORIES
+                .iter()
+                .find(|(category_name, _)| *category_name == name)
+                .ok_or_else(|| format!("Unknown log category {}", name))?
+                .1;
+            set_level(category, level);
+        }
+    }
+    Ok(())
+}
+
+fn parse_level(name: &str) -> Result<Option<Level>, String> {
+    if name == "off" {
+        return Ok(None);
+    }
+    LEVELS
+        .iter()
+        .find(|(level_name, _)| *level_name == name)
+        .map(|(_, level)| Some(*level))
+        .ok_or_else(|| format!("Unknown log level {}", name))
+}
+
+/// Prints to stderr if `category` is enabled at `level`, the arguments are
+/// only evaluated then.
+macro_rules! log {
+    ($category:ident, $level:ident, $($arg:tt)*) => {
+        if $crate::logging::enabled(
+            $crate::logging::Category::$category,
+            $crate::logging::Level::$level,
+        ) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use log;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels() -> Vec<u8> {
+        ENABLED
+            .iter()
+            .map(|level| level.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    // The levels are global, so every spec is checked in one test
+    #[test]
+    fn configure_parses_specs() {
+        configure("guest,host=debug, svc=info").unwrap();
+        assert_eq!(levels(), [3, 2, 0, 0, 1]);
+        assert!(enabled(Category::Svc, Level::Info));
+        assert!(!enabled(Category::Svc, Level::Debug));
+
+        configure("debug,regs=off").unwrap();
+        assert_eq!(levels(), [2, 2, 2, 0, 2]);
+        configure("all=info,block").unwrap();
+        assert_eq!(levels(), [1, 1, 3, 1, 1]);
+        configure("").unwrap();
+        assert_eq!(levels(), [1, 1, 3, 1, 1]);
+        configure("off").unwrap();
+        assert_eq!(levels(), [0; 5]);
+
+        assert_eq!(
+            configure("kernel"),
+            Err("Unknown log category kernel".into())
+        );
+        assert_eq!(configure("=info"), Err("Unknown log category ".into()));
+        assert_eq!(
+            configure("guest=loud"),
+            Err("Unknown log level loud".into())
+        );
+        assert_eq!(configure("all="), Err("Unknown log level ".into()));
+        // Entries before a malformed one still apply
+        configure("host,svc=verbose").unwrap_err();
+        assert_eq!(levels(), [0, 3, 0, 0, 0]);
+        configure("off").unwrap();
+    }
+}
@@ -1,24 +1,77 @@
 extern crate core;
 
+mod error;
 mod jit;
+mod logging;
+mod memory;
 mod parser;
 
+use crate::error::{Error, Result};
+use crate::logging::log;
+use crate::memory::{GuestMemory, LOAD_BASE};
 use std::env;
 use std::process::exit;
 
 fn main() {
+    if let Err(error) = run() {
+        eprintln!("Error: {}", error);
+        exit(1);
+    }
+}
+
+fn run() -> Result<()> {
     let args = env::args().collect::<Vec<String>>();
 
-    if args.len() != 2 {
-        println!("Usage: {} <path-to-nro>", args[0]);
+    let usage = || -> ! {
+        eprintln!("Usage: {} [--verify] [--log <spec>] <path-to-nro>", args[0]);
         exit(1);
+    };
+
+    // The spec from the command line applies on top of the environment one
+    let mut log_specs = env::var(logging::LOG_ENV)
+        .into_iter()
+        .collect::<Vec<String>>();
+    let mut verify = false;
+    let mut path = None;
+    let mut options = args[1..].iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--verify" => verify = true,
+            "--log" => log_specs.push(options.next().unwrap_or_else(|| usage()).clone()),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    for spec in &log_specs {
+        if let Err(err) = logging::configure(spec) {
+            eprintln!("Error: {}", err);
+            usage();
+        }
     }
 
-    let nro = parser::nro::parse(&args[1]).unwrap();
+    let nro = parser::nro::parse(path).map_err(Error::Loader)?;
+    let memory = GuestMemory::from_nro(&nro).map_err(Error::Loader)?;
 
-    let text_segment = nro.get_segment(&nro.header.text_segment_header).unwrap();
-    let (_, text_content, _) = unsafe { text_segment.align_to::<u32>() };
+    for (name, region) in [
+        ("image", memory.image()),
+        ("heap", memory.heap()),
+        ("stack", memory.stack()),
+    ] {
+        log!(
+            Block,
+            Info,
+            "{}: {:#x}-{:#x}",
+            name,
+            region.start,
+            region.end()
+        );
+    }
 
-    let mut jit = jit::context::Context::new(text_content.to_vec());
-    jit.run();
+    let mut jit = jit::context::Context::new(memory, LOAD_BASE)?;
+    jit.verify = verify;
+    let exit = jit.run()?;
+    log!(Block, Info, "Guest exited: {:?}", exit);
+    Ok(())
 }
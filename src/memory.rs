@@ -0,0 +1,216 @@
+use crate::parser::nro::Nro;
+use memmap::MmapMut;
+use std::fmt::Formatter;
+use std::ops::{Range, RangeInclusive};
+use std::{fmt, io, result};
+
+/// Guest address the NRO image is mapped at.
+pub const LOAD_BASE: u64 = 0x10000;
+pub const PAGE_SIZE: u64 = 0x1000;
+pub const HEAP_SIZE: u64 = 0x2000000;
+pub const STACK_SIZE: u64 = 0x100000;
+/// Largest image `GuestMemory` maps. Generated code bounds checks guest
+/// addresses against the mapping size with a 32-bit immediate.
+pub const MAX_IMAGE_SIZE: u64 = 0x40000000;
+
+/// A guest access outside of the mapped address space.
+#[derive(Copy, Clone)]
+pub struct Fault {
+    pub addr: u64,
+    pub size: usize,
+}
+
+impl fmt::Debug for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Guest memory fault at {:#x} ({} bytes)",
+            self.addr, self.size
+        )
+    }
+}
+
+pub type Result<T> = result::Result<T, Fault>;
+
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+/// The guest address space. The image, heap and stack are laid out back to
+/// back in a single host mapping starting at `LOAD_BASE`, so a guest address
+/// translates to a host address by adding a constant offset.
+pub struct GuestMemory {
+    mem: MmapMut,
+    /// One byte per page, non-zero while the page holds translated code. Store
+    /// emitters test it to catch writes to code.
+    code_pages: Vec<u8>,
+    image: Region,
+    heap: Region,
+    stack: Region,
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+impl GuestMemory {
+    pub fn new(image_size: u64) -> io::Result<Self> {
+        if image_size > MAX_IMAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Image of {:#x} bytes is too large to map", image_size),
+            ));
+        }
+        let image = Region {
+            start: LOAD_BASE,
+            size: align_up(image_size, PAGE_SIZE),
+        };
+        let heap = Region {
+            start: image.end(),
+            size: HEAP_SIZE,
+        };
+        let stack = Region {
+            start: heap.end(),
+            size: STACK_SIZE,
+        };
+        let mem = MmapMut::map_anon((stack.end() - LOAD_BASE) as usize)?;
+        let code_pages = vec![0; mem.len() / PAGE_SIZE as usize];
+
+        Ok(GuestMemory {
+            mem,
+            code_pages,
+            image,
+            heap,
+            stack,
+        })
+    }
+
+    /// Maps .text, .rodata and .data at their memory offsets and reserves .bss
+    /// right after .data.
+    pub fn from_nro(nro: &Nro) -> io::Result<Self> {
+        let header = &nro.header;
+        let data_end = header.data_segment_header.memory_offset as u64
+            + header.data_segment_header.size as u64;
+        let mut memory = GuestMemory::new(data_end + header.bss_size as u64)?;
+
+        for segment_header in [
+            &header.text_segment_header,
+            &header.ro_segment_header,
+            &header.data_segment_header,
+        ] {
+            let segment = nro.get_segment(segment_header)?;
+            let addr = LOAD_BASE + segment_header.memory_offset as u64;
+            memory.write_bytes(addr, &segment).map_err(|fault| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", fault))
+            })?;
+        }
+        Ok(memory)
+    }
+
+    pub fn image(&self) -> Region {
+        self.image
+    }
+
+    pub fn heap(&self) -> Region {
+        self.heap
+    }
+
+    pub fn stack(&self) -> Region {
+        self.stack
+    }
+
+    /// Initial stack pointer, the stack grows down from the end of its region.
+    pub fn stack_top(&self) -> u64 {
+        self.stack.end()
+    }
+
+    fn offset(&self, addr: u64, size: usize) -> Result<usize> {
+        let fault = Fault { addr, size };
+        let offset = addr.checked_sub(LOAD_BASE).ok_or(fault)?;
+        match offset.checked_add(size as u64) {
+            Some(end) if end <= self.mem.len() as u64 => Ok(offset as usize),
+            _ => Err(fault),
+        }
+    }
+
+    pub fn read_bytes(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let offset = self.offset(addr, buf.len())?;
+        buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+        let offset = self.offset(addr, buf.len())?;
+        self.mem[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    #[cfg(test)]
+    pub fn read_u64(&self, addr: u64) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.read_bytes(addr, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    #[cfg(test)]
+    pub fn write_u32(&mut self, addr: u64, value: u32) -> Result<()> {
+        self.write_bytes(addr, &value.to_le_bytes())
+    }
+
+    #[cfg(test)]
+    pub fn write_u64(&mut self, addr: u64, value: u64) -> Result<()> {
+        self.write_bytes(addr, &value.to_le_bytes())
+    }
+
+    /// Host address backing `LOAD_BASE`.
+    pub fn host_base(&self) -> u64 {
+        self.mem.as_ptr() as u64
+    }
+
+    /// Host address of the code page table, indexed by guest page number
+    /// counted from `LOAD_BASE`.
+    pub fn code_pages_addr(&self) -> u64 {
+        self.code_pages.as_ptr() as u64
+    }
+
+    /// Code page table indices of the pages overlapping the non-empty `range`.
+    fn code_page_indices(range: Range<u64>) -> RangeInclusive<usize> {
+        let first = ((range.start - LOAD_BASE) / PAGE_SIZE) as usize;
+        let last = ((range.end - 1 - LOAD_BASE) / PAGE_SIZE) as usize;
+        first..=last
+    }
+
+    /// Marks the pages overlapping `range` as holding translated code or not.
+    pub fn set_code_pages(&mut self, range: Range<u64>, has_code: bool) {
+        if !range.is_empty() {
+            self.code_pages[Self::code_page_indices(range)].fill(has_code as u8);
+        }
+    }
+
+    /// Whether any page overlapping `range` holds translated code.
+    pub fn has_code_pages(&self, range: Range<u64>) -> bool {
+        !range.is_empty()
+            && self.code_pages[Self::code_page_indices(range)]
+                .iter()
+                .any(|&page| page != 0)
+    }
+
+    /// Size of the whole address space starting at `LOAD_BASE`.
+    pub fn size(&self) -> u64 {
+        self.mem.len() as u64
+    }
+}
@@ -53,9 +53,8 @@ impl Nro {
         let offset = segment.memory_offset as usize;
         let mut buf = vec![0u8; size];
 
-        let read_len = self
-            .file
-            .read_at(buf.borrow_mut(), (size_of::<NroHeader>() + offset) as u64)?;
+        // Segment memory offsets double as file offsets, .text includes the header
+        let read_len = self.file.read_at(buf.borrow_mut(), offset as u64)?;
         if read_len == size {
             Ok(buf)
         } else {